@@ -0,0 +1,83 @@
+use std::fmt::Display;
+use std::io::IsTerminal;
+
+use crate::progress::{BarProgress, NoProgress, ProgressObserver};
+
+/// Terminal output shared between the workers processing collection items
+pub struct Console {
+    /// The number of items, if known up front
+    total: Option<usize>,
+    /// Progress bars are only drawn when writing to a terminal
+    bars: Option<indicatif::MultiProgress>,
+}
+
+impl Console {
+    pub fn new(total: Option<usize>) -> Self {
+        let bars = if std::io::stdout().is_terminal() {
+            Some(indicatif::MultiProgress::with_draw_target(
+                indicatif::ProgressDrawTarget::stdout(),
+            ))
+        } else {
+            None
+        };
+        Console { total, bars }
+    }
+
+    pub fn item(&self, index: usize) -> ItemConsole<'_> {
+        let prefix = match self.total {
+            Some(total) => {
+                let width = total.to_string().len();
+                format!("[{:>width$}/{}]", index + 1, total, width = width)
+            }
+            None => format!("[{}]", index + 1),
+        };
+        ItemConsole {
+            console: self,
+            prefix,
+        }
+    }
+
+    pub fn println<D: Display>(&self, message: D) {
+        let message = message.to_string();
+        match &self.bars {
+            // printing through the bars keeps them from being drawn over
+            Some(bars) => {
+                let _ = bars.println(message);
+            }
+            // `println!` holds the stdout lock for the whole line,
+            // so lines from different workers never interleave
+            None => println!("{}", message),
+        }
+    }
+}
+
+/// Output for a single collection item, tagged so that it stays readable
+/// when several items are being processed at once
+pub struct ItemConsole<'a> {
+    console: &'a Console,
+    prefix: String,
+}
+
+impl ItemConsole<'_> {
+    pub fn println<D: Display>(&self, message: D) {
+        self.console.println(format!("{} {}", self.prefix, message));
+    }
+
+    pub fn progress(&self) -> Box<dyn ProgressObserver> {
+        match &self.console.bars {
+            Some(bars) => {
+                let bar = bars.add(indicatif::ProgressBar::new(0));
+                bar.set_style(
+                    indicatif::ProgressStyle::with_template(
+                        "{prefix} [{bar:30}] {bytes}/{total_bytes} {msg}",
+                    )
+                    .unwrap()
+                    .progress_chars("=> "),
+                );
+                bar.set_prefix(self.prefix.clone());
+                Box::new(BarProgress(bar))
+            }
+            None => Box::new(NoProgress),
+        }
+    }
+}
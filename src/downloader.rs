@@ -1,36 +1,481 @@
+use futures_util::StreamExt;
 use reqwest::cookie::CookieStore;
 use serde::Serialize;
 
+use crate::parser::{parse_download_status, DownloadStatus, ParsePageError};
+use crate::progress::{ProgressObserver, ProgressWriter};
+use crate::rate_limiter::{BandwidthLimiter, DailyWindow, RateLimiter};
+use crate::recorder::Recorder;
+use crate::types::ArtSize;
+
 const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::new(10, 0);
 const MEDIA_TIMEOUT: std::time::Duration = std::time::Duration::new(30 * 60, 0);
+const PREPARE_TIMEOUT: std::time::Duration = std::time::Duration::new(10 * 60, 0);
+const DEFAULT_USER_AGENT: &str = "bandcamper";
+const DEFAULT_BASE_URL: &str = "https://bandcamp.com";
+const DEFAULT_ART_BASE_URL: &str = "https://f4.bcbits.com/img/";
+
+/// How the HTTP client reaches Bandcamp
+#[derive(Clone, Debug)]
+pub struct DownloaderConfig {
+    /// An `http://`, `https://`, `socks5://` or `socks5h://` proxy for all requests
+    pub proxy: Option<String>,
+    /// PEM files of certificates to trust in addition to the system roots
+    pub root_certificates: Vec<std::path::PathBuf>,
+    /// The local address to send requests from
+    pub local_address: Option<std::net::IpAddr>,
+    /// Timeout for page and API requests
+    pub timeout: std::time::Duration,
+    /// Timeout for media transfers, cover art included
+    pub media_timeout: std::time::Duration,
+    /// How long to wait for Bandcamp to prepare a download
+    pub prepare_timeout: std::time::Duration,
+    pub user_agent: String,
+    /// The site that pages, the API and cookies belong to
+    pub base_url: reqwest::Url,
+    /// Where cover art is served from
+    pub art_base_url: reqwest::Url,
+    /// Record every exchange to disk, or serve them back instead of using the network
+    pub recorder: Option<std::sync::Arc<Recorder>>,
+}
+
+impl Default for DownloaderConfig {
+    fn default() -> Self {
+        DownloaderConfig {
+            proxy: None,
+            root_certificates: vec![],
+            local_address: None,
+            timeout: DEFAULT_TIMEOUT,
+            media_timeout: MEDIA_TIMEOUT,
+            prepare_timeout: PREPARE_TIMEOUT,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            base_url: DEFAULT_BASE_URL.parse().unwrap(),
+            art_base_url: DEFAULT_ART_BASE_URL.parse().unwrap(),
+            recorder: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DownloaderConfigError {
+    BadCertificateFile(std::path::PathBuf, std::io::Error),
+    NoCertificates(std::path::PathBuf),
+    InvalidCertificate(std::path::PathBuf, reqwest::Error),
+    InvalidProxy(String, reqwest::Error),
+    BadClient(reqwest::Error),
+}
+
+impl std::fmt::Display for DownloaderConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DownloaderConfigError::BadCertificateFile(path, e) => {
+                write!(f, "could not read certificate file {:?}: {}", path, e)
+            }
+            DownloaderConfigError::NoCertificates(path) => {
+                write!(
+                    f,
+                    "certificate file {:?} contains no PEM certificates",
+                    path
+                )
+            }
+            DownloaderConfigError::InvalidCertificate(path, e) => {
+                write!(f, "certificate file {:?} is invalid: {}", path, e)
+            }
+            DownloaderConfigError::InvalidProxy(proxy, e) => {
+                write!(f, "invalid proxy {:?}: {}", proxy, e)
+            }
+            DownloaderConfigError::BadClient(e) => write!(f, "could not create HTTP client: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DownloaderConfigError {}
+
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+/// Split a PEM bundle into its individual certificates
+fn split_pem_certificates(pem: &str) -> Vec<&str> {
+    let mut certificates = vec![];
+    let mut rest = pem;
+    while let Some(begin) = rest.find(PEM_BEGIN) {
+        let end = match rest[begin..].find(PEM_END) {
+            Some(end) => begin + end + PEM_END.len(),
+            None => break,
+        };
+        certificates.push(&rest[begin..end]);
+        rest = &rest[end..];
+    }
+    certificates
+}
+
+fn load_root_certificates(
+    path: &std::path::Path,
+) -> Result<Vec<reqwest::Certificate>, DownloaderConfigError> {
+    let pem = std::fs::read_to_string(path)
+        .map_err(|e| DownloaderConfigError::BadCertificateFile(path.to_path_buf(), e))?;
+    let certificates: Vec<reqwest::Certificate> = split_pem_certificates(&pem)
+        .into_iter()
+        .map(|certificate| {
+            reqwest::Certificate::from_pem(certificate.as_bytes())
+                .map_err(|e| DownloaderConfigError::InvalidCertificate(path.to_path_buf(), e))
+        })
+        .collect::<Result<_, _>>()?;
+    if certificates.is_empty() {
+        return Err(DownloaderConfigError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certificates)
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: std::time::Duration,
+    pub max_delay: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: std::time::Duration::new(1, 0),
+            max_delay: std::time::Duration::new(60, 0),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter for the given (1-based) failed attempt
+    fn backoff(&self, attempt: u32) -> std::time::Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = exponential / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Requests per second allowed across all clones of a `Downloader`
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    pub page_requests: f64,
+    pub media_requests: f64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            page_requests: 2f64,
+            media_requests: 0.5f64,
+        }
+    }
+}
+
+/// Media bytes per second allowed across all clones of a `Downloader`
+#[derive(Clone, Debug, Default)]
+pub struct BandwidthLimit {
+    /// Zero (or less) for no limit
+    pub bytes_per_second: f64,
+    /// Hours of the day during which the limit is lifted
+    pub full_speed: Option<DailyWindow>,
+}
+
+fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+}
+
+fn is_retryable_error(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => is_retryable_status(status),
+        None => err.is_timeout() || err.is_connect() || err.is_request(),
+    }
+}
+
+/// How long a Retry-After value asks to wait, given in seconds or as an HTTP date
+fn parse_retry_after(
+    value: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<std::time::Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(std::time::Duration::new(seconds, 0));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    // a date that has already passed asks for no wait at all
+    Some(
+        (date.with_timezone(&chrono::Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// The wait the server asks for, but never longer than `max_delay`
+fn get_retry_after(
+    response: &reqwest::Response,
+    max_delay: std::time::Duration,
+) -> Option<std::time::Duration> {
+    let status = response.status();
+    if status != reqwest::StatusCode::TOO_MANY_REQUESTS
+        && status != reqwest::StatusCode::SERVICE_UNAVAILABLE
+    {
+        return None;
+    }
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?;
+    parse_retry_after(value, chrono::Utc::now()).map(|delay| delay.min(max_delay))
+}
 
 lazy_static::lazy_static! {
     static ref RE_CD: regex::bytes::Regex = regex::bytes::Regex::new(r#"filename\s*=\s*"([^"]+)""#).unwrap();
+    static ref RE_CR: regex::bytes::Regex = regex::bytes::Regex::new(r#"^bytes\s+([0-9]+)-([0-9]+)/([0-9]+)$"#).unwrap();
+    /// Drives the async client on behalf of the blocking API
+    static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
 }
 
+type ByteStream =
+    std::pin::Pin<Box<dyn futures_util::Stream<Item = reqwest::Result<bytes::Bytes>> + Send>>;
+
 pub trait MediaDownload {
     fn get_filename(&self) -> &str;
-    fn save<W: ?Sized + std::io::Write>(&mut self, writer: &mut W) -> Result<(), reqwest::Error>;
+    /// The size of the complete file, regardless of where the transfer starts
+    fn get_content_length(&self) -> u64;
+    /// The ETag or Last-Modified date identifying this version of the file
+    fn get_validator(&self) -> Option<&str>;
+    /// Restart the transfer at `offset` bytes into the file.
+    /// Returns the offset the transfer will actually start from,
+    /// which is 0 if the server does not support ranged requests
+    /// or the file no longer matches `validator`.
+    fn resume_from(&mut self, offset: u64, validator: Option<&str>) -> Result<u64, GetMediaError>;
+    /// The remaining body of the transfer, for callers that process it as it arrives
+    fn reader(&mut self) -> Box<dyn std::io::Read + '_>;
+    fn save<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        observer: &mut dyn ProgressObserver,
+    ) -> Result<(), std::io::Error>;
 }
 
-pub struct ReqwestMediaDownload {
+pub struct AsyncMediaDownload {
+    downloader: AsyncDownloader,
+    url: String,
+    offset: u64,
     content_length: u64,
     filename: String,
-    response: reqwest::blocking::Response,
+    validator: Option<String>,
+    body: ByteStream,
 }
 
-impl MediaDownload for ReqwestMediaDownload {
-    fn get_filename(&self) -> &str {
+impl AsyncMediaDownload {
+    pub fn get_filename(&self) -> &str {
         &self.filename
     }
 
-    fn save<W: ?Sized + std::io::Write>(&mut self, writer: &mut W) -> Result<(), reqwest::Error> {
-        let count = self.response.copy_to(writer)?;
-        assert!(count == self.content_length);
+    pub fn get_content_length(&self) -> u64 {
+        self.content_length
+    }
+
+    pub fn get_validator(&self) -> Option<&str> {
+        self.validator.as_deref()
+    }
+
+    pub async fn resume_from(
+        &mut self,
+        offset: u64,
+        validator: Option<&str>,
+    ) -> Result<u64, GetMediaError> {
+        let response = self
+            .downloader
+            .send(&self.downloader.media_limiter, |client| {
+                let request = client
+                    .get(&self.url)
+                    .timeout(self.downloader.media_timeout)
+                    .header(reqwest::header::RANGE, format!("bytes={}-", offset));
+                match validator {
+                    Some(validator) => request.header(reqwest::header::IF_RANGE, validator),
+                    None => request,
+                }
+            })
+            .await
+            .map_err(GetMediaError::RequestError)?;
+        let (start, total) = if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+            let content_range = response
+                .headers()
+                .get(reqwest::header::CONTENT_RANGE)
+                .ok_or_else(|| GetMediaError::InvalidContentRange(vec![]))?;
+            parse_content_range(content_range.as_bytes())
+                .filter(|(start, _)| *start == offset)
+                .ok_or_else(|| {
+                    GetMediaError::InvalidContentRange(content_range.as_bytes().into())
+                })?
+        } else {
+            // the server ignored the range or the file changed, so this is a full download
+            (0, get_content_length(&response)?)
+        };
+        if total != self.content_length {
+            return Err(GetMediaError::ContentLengthChanged(
+                self.content_length,
+                total,
+            ));
+        }
+        self.offset = start;
+        if let Some(validator) = get_validator(&response) {
+            self.validator = Some(validator);
+        }
+        self.body = self.downloader.body_stream(response);
+        Ok(start)
+    }
+
+    /// The remaining body of the transfer, as it arrives
+    pub fn bytes_stream(&mut self) -> &mut ByteStream {
+        &mut self.body
+    }
+
+    pub async fn save<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        observer: &mut dyn ProgressObserver,
+    ) -> Result<(), std::io::Error> {
+        let mut writer = ProgressWriter::new(writer, observer, self.offset, self.content_length);
+        let mut count = 0;
+        while let Some(chunk) = self.body.next().await {
+            let chunk = chunk.map_err(std::io::Error::other)?;
+            std::io::Write::write_all(&mut writer, &chunk)?;
+            count += chunk.len() as u64;
+        }
+        writer.finish();
+        if count != self.content_length - self.offset {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "expected {} bytes but received {}",
+                    self.content_length - self.offset,
+                    count
+                ),
+            ));
+        }
         Ok(())
     }
 }
 
+/// Adapts the body of an async download to `std::io::Read`
+struct BlockingBody<'a> {
+    body: &'a mut ByteStream,
+    chunk: bytes::Bytes,
+}
+
+impl std::io::Read for BlockingBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match RUNTIME.block_on(self.body.next()) {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(e)) => return Err(std::io::Error::other(e)),
+                None => return Ok(0),
+            }
+        }
+        let count = buf.len().min(self.chunk.len());
+        buf[..count].copy_from_slice(&self.chunk.split_to(count));
+        Ok(count)
+    }
+}
+
+/// A blocking wrapper around `AsyncMediaDownload`
+pub struct ReqwestMediaDownload {
+    inner: AsyncMediaDownload,
+}
+
+impl MediaDownload for ReqwestMediaDownload {
+    fn get_filename(&self) -> &str {
+        self.inner.get_filename()
+    }
+
+    fn get_content_length(&self) -> u64 {
+        self.inner.get_content_length()
+    }
+
+    fn get_validator(&self) -> Option<&str> {
+        self.inner.get_validator()
+    }
+
+    fn resume_from(&mut self, offset: u64, validator: Option<&str>) -> Result<u64, GetMediaError> {
+        RUNTIME.block_on(self.inner.resume_from(offset, validator))
+    }
+
+    fn reader(&mut self) -> Box<dyn std::io::Read + '_> {
+        Box::new(BlockingBody {
+            body: self.inner.bytes_stream(),
+            chunk: bytes::Bytes::new(),
+        })
+    }
+
+    fn save<W: ?Sized + std::io::Write>(
+        &mut self,
+        writer: &mut W,
+        observer: &mut dyn ProgressObserver,
+    ) -> Result<(), std::io::Error> {
+        RUNTIME.block_on(self.inner.save(writer, observer))
+    }
+}
+
+fn get_content_length(response: &reqwest::Response) -> Result<u64, GetMediaError> {
+    let content_length_h = response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .ok_or(GetMediaError::NoContentLength)?;
+    content_length_h
+        .to_str()
+        .map_err(|_| GetMediaError::InvalidContentLength(content_length_h.as_bytes().into()))?
+        .parse()
+        .map_err(|_| GetMediaError::InvalidContentLength(content_length_h.as_bytes().into()))
+}
+
+/// A strong ETag, or else the Last-Modified date, to resume the file with
+fn get_validator(response: &reqwest::Response) -> Option<String> {
+    let headers = response.headers();
+    headers
+        .get(reqwest::header::ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(reqwest::header::LAST_MODIFIED))
+        .and_then(|validator| validator.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Bandcamp answers with a web page instead of the file while it is still preparing it
+fn is_preparing(response: &reqwest::Response) -> bool {
+    !response
+        .headers()
+        .contains_key(reqwest::header::CONTENT_DISPOSITION)
+        && response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("text/html"))
+}
+
+/// The endpoint that reports whether the file behind a media URL is ready
+fn get_status_url(url: &str) -> Option<String> {
+    let mut url: reqwest::Url = url.parse().ok()?;
+    let path = url.path().strip_prefix("/download/")?.to_string();
+    url.set_path(&format!("/statdownload/{}", path));
+    url.query_pairs_mut().append_pair(".vrs", "1");
+    Some(url.into())
+}
+
+fn parse_content_range(content_range: &[u8]) -> Option<(u64, u64)> {
+    let capture = RE_CR.captures(content_range)?;
+    let start = std::str::from_utf8(&capture[1]).ok()?.parse().ok()?;
+    let total = std::str::from_utf8(&capture[3]).ok()?.parse().ok()?;
+    Some((start, total))
+}
+
 #[derive(Debug)]
 pub enum GetMediaError {
     RequestError(reqwest::Error),
@@ -39,68 +484,306 @@ pub enum GetMediaError {
     NoContentLength,
     InvalidContentLength(Vec<u8>),
     InvalidFilename(Vec<u8>, std::str::Utf8Error),
+    InvalidContentRange(Vec<u8>),
+    ContentLengthChanged(u64, u64),
+    InvalidDownloadStatus(ParsePageError),
+    PreparationFailed(String),
+    PreparationTimedOut(std::time::Duration),
 }
 
+impl std::fmt::Display for GetMediaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GetMediaError::RequestError(e) => write!(f, "media request failed: {}", e),
+            GetMediaError::NoContentDisposition(headers) => write!(
+                f,
+                "media response has no Content-Disposition header (headers: {})",
+                headers
+            ),
+            GetMediaError::InvalidContentDisposition(value) => write!(
+                f,
+                "invalid Content-Disposition header {:?}",
+                String::from_utf8_lossy(value)
+            ),
+            GetMediaError::NoContentLength => {
+                write!(f, "media response has no Content-Length header")
+            }
+            GetMediaError::InvalidContentLength(value) => write!(
+                f,
+                "invalid Content-Length header {:?}",
+                String::from_utf8_lossy(value)
+            ),
+            GetMediaError::InvalidFilename(value, e) => write!(
+                f,
+                "filename {:?} is not valid UTF-8: {}",
+                String::from_utf8_lossy(value),
+                e
+            ),
+            GetMediaError::InvalidContentRange(value) => write!(
+                f,
+                "invalid Content-Range header {:?}",
+                String::from_utf8_lossy(value)
+            ),
+            GetMediaError::InvalidDownloadStatus(e) => {
+                write!(f, "invalid download status: {}", e)
+            }
+            GetMediaError::PreparationFailed(error) => {
+                write!(f, "Bandcamp could not prepare the download: {}", error)
+            }
+            GetMediaError::PreparationTimedOut(timeout) => write!(
+                f,
+                "download was still being prepared after {} seconds",
+                timeout.as_secs()
+            ),
+            GetMediaError::ContentLengthChanged(before, after) => write!(
+                f,
+                "file size changed from {} to {} bytes between requests",
+                before, after
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GetMediaError {}
+
 #[derive(Clone)]
-pub struct Downloader {
-    client: reqwest::blocking::Client,
+pub struct AsyncDownloader {
+    client: reqwest::Client,
+    media_timeout: std::time::Duration,
+    prepare_timeout: std::time::Duration,
+    retry_policy: RetryPolicy,
+    page_limiter: std::sync::Arc<RateLimiter>,
+    media_limiter: std::sync::Arc<RateLimiter>,
+    bandwidth_limiter: std::sync::Arc<BandwidthLimiter>,
+    recorder: Option<std::sync::Arc<Recorder>>,
+    base_url: reqwest::Url,
+    art_base_url: reqwest::Url,
 }
 
-impl Downloader {
-    pub fn new(cookies: Vec<reqwest::header::HeaderValue>) -> reqwest::Result<Self> {
-        let url = "https://bandcamp.com".parse::<reqwest::Url>().unwrap();
+impl AsyncDownloader {
+    pub fn new(
+        cookies: Vec<reqwest::header::HeaderValue>,
+        config: &DownloaderConfig,
+    ) -> Result<Self, DownloaderConfigError> {
         let jar = std::sync::Arc::new(reqwest::cookie::Jar::default());
-        jar.set_cookies(&mut cookies.iter(), &url);
-        let builder = reqwest::blocking::Client::builder()
-            .user_agent("bandcamper")
+        jar.set_cookies(&mut cookies.iter(), &config.base_url);
+        let mut builder = reqwest::Client::builder()
+            .user_agent(&config.user_agent)
             .gzip(true)
             .cookie_store(true)
             .cookie_provider(jar)
-            .timeout(DEFAULT_TIMEOUT);
-        let client = builder.build()?;
-        Ok(Downloader { client })
+            .timeout(config.timeout)
+            .local_address(config.local_address);
+        if let Some(proxy) = &config.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|e| DownloaderConfigError::InvalidProxy(proxy.clone(), e))?;
+            builder = builder.proxy(proxy);
+        }
+        for path in config.root_certificates.iter() {
+            for certificate in load_root_certificates(path)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        let client = builder.build().map_err(DownloaderConfigError::BadClient)?;
+        Ok(AsyncDownloader {
+            client,
+            media_timeout: config.media_timeout,
+            prepare_timeout: config.prepare_timeout,
+            retry_policy: RetryPolicy::default(),
+            page_limiter: std::sync::Arc::new(RateLimiter::unlimited()),
+            media_limiter: std::sync::Arc::new(RateLimiter::unlimited()),
+            bandwidth_limiter: std::sync::Arc::new(BandwidthLimiter::unlimited()),
+            recorder: config.recorder.clone(),
+            base_url: config.base_url.clone(),
+            art_base_url: config.art_base_url.clone(),
+        })
+    }
+
+    pub fn base_url(&self) -> &reqwest::Url {
+        &self.base_url
+    }
+
+    /// Whether responses come from a recording, which has no media in it
+    pub fn is_replaying(&self) -> bool {
+        self.recorder
+            .as_ref()
+            .is_some_and(|recorder| recorder.is_replaying())
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_rate_limits(mut self, rate_limits: &RateLimits) -> Self {
+        // a burst of one request keeps back-to-back calls evenly spaced
+        self.page_limiter = std::sync::Arc::new(RateLimiter::new(rate_limits.page_requests, 1f64));
+        self.media_limiter =
+            std::sync::Arc::new(RateLimiter::new(rate_limits.media_requests, 1f64));
+        self
+    }
+
+    pub fn with_bandwidth_limit(mut self, bandwidth_limit: &BandwidthLimit) -> Self {
+        self.bandwidth_limiter = std::sync::Arc::new(BandwidthLimiter::new(
+            bandwidth_limit.bytes_per_second,
+            bandwidth_limit.full_speed.clone(),
+        ));
+        self
+    }
+
+    /// The body of a media response, throttled to the bandwidth limit
+    fn body_stream(&self, response: reqwest::Response) -> ByteStream {
+        let limiter = self.bandwidth_limiter.clone();
+        Box::pin(response.bytes_stream().then(move |chunk| {
+            let limiter = limiter.clone();
+            async move {
+                if let Ok(bytes) = &chunk {
+                    tokio::time::sleep(limiter.reserve(bytes.len())).await;
+                }
+                chunk
+            }
+        }))
     }
 
-    pub fn get_page(&self, url: &str) -> reqwest::Result<reqwest::blocking::Response> {
-        self.client
-            .get(url)
-            .send()
-            .and_then(|r| r.error_for_status())
+    /// Send the request produced by `build`, retrying transient failures
+    async fn send<F>(&self, limiter: &RateLimiter, build: F) -> reqwest::Result<reqwest::Response>
+    where
+        F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 1;
+        loop {
+            tokio::time::sleep(limiter.reserve(1f64)).await;
+            let delay = match self.execute(build(&self.client)).await {
+                Ok(response) => match response.error_for_status_ref() {
+                    Ok(_) => return Ok(response),
+                    Err(err) => {
+                        if attempt >= self.retry_policy.max_attempts || !is_retryable_error(&err) {
+                            return Err(err);
+                        }
+                        get_retry_after(&response, self.retry_policy.max_delay)
+                            .unwrap_or_else(|| self.retry_policy.backoff(attempt))
+                    }
+                },
+                Err(err) => {
+                    if attempt >= self.retry_policy.max_attempts || !is_retryable_error(&err) {
+                        return Err(err);
+                    }
+                    self.retry_policy.backoff(attempt)
+                }
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
     }
 
-    pub fn post_api<T: Serialize + ?Sized>(
+    /// Every request goes through here, so that it can be recorded or replayed
+    async fn execute(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        let request = request.build()?;
+        match &self.recorder {
+            Some(recorder) => recorder.execute(&self.client, request).await,
+            None => self.client.execute(request).await,
+        }
+    }
+
+    pub async fn get_page(&self, url: &str) -> reqwest::Result<reqwest::Response> {
+        self.send(&self.page_limiter, |client| client.get(url))
+            .await
+    }
+
+    pub async fn post_api<T: Serialize + ?Sized>(
         &self,
         url: &str,
         json: &T,
-    ) -> reqwest::Result<reqwest::blocking::Response> {
-        self.client
-            .post(url)
-            .json(json)
-            .send()
-            .and_then(|r| r.error_for_status())
+    ) -> reqwest::Result<reqwest::Response> {
+        self.send(&self.page_limiter, |client| client.post(url).json(json))
+            .await
     }
 
-    pub fn get_media(&self, url: &str) -> Result<ReqwestMediaDownload, GetMediaError> {
-        let response = self
-            .client
-            .get(url)
-            .timeout(MEDIA_TIMEOUT)
-            .send()
-            .map_err(GetMediaError::RequestError)?
-            .error_for_status()
-            .map_err(GetMediaError::RequestError)?;
-        let content_disposition_x = response.headers().get(reqwest::header::CONTENT_DISPOSITION);
-        let content_disposition = match content_disposition_x {
-            Some(cd) => Ok(cd),
-            _ => {
-                // let mut fff = std::fs::File::create("/tmp/error.html").unwrap();
-                // response.copy_to(&mut fff).unwrap();
-                Err(GetMediaError::NoContentDisposition(format!(
-                    "{:?}",
-                    &response.headers()
-                )))
+    pub async fn get_art(&self, art_id: u64, size: &ArtSize) -> reqwest::Result<reqwest::Response> {
+        let url = self
+            .art_base_url
+            .join(&format!("a{}_{}.jpg", art_id, size.code()))
+            .unwrap();
+        // large art can take longer to transfer than a page is allowed
+        self.send(&self.page_limiter, |client| {
+            client.get(url.clone()).timeout(self.media_timeout)
+        })
+        .await
+    }
+
+    pub async fn get_media(&self, url: &str) -> Result<AsyncMediaDownload, GetMediaError> {
+        let started = std::time::Instant::now();
+        let mut url = url.to_string();
+        let mut attempt = 1;
+        loop {
+            let response = self
+                .send(&self.media_limiter, |client| {
+                    client.get(&url).timeout(self.media_timeout)
+                })
+                .await
+                .map_err(GetMediaError::RequestError)?;
+            let status_url = match get_status_url(&url) {
+                Some(status_url) if is_preparing(&response) => status_url,
+                _ => return self.media_from_response(url, response),
+            };
+            url = self
+                .wait_until_prepared(&url, &status_url, started, &mut attempt)
+                .await?;
+        }
+    }
+
+    /// Poll the status of a download until Bandcamp has prepared it,
+    /// returning the URL to fetch it from
+    async fn wait_until_prepared(
+        &self,
+        url: &str,
+        status_url: &str,
+        started: std::time::Instant,
+        attempt: &mut u32,
+    ) -> Result<String, GetMediaError> {
+        loop {
+            let delay = self.retry_policy.backoff(*attempt);
+            if started.elapsed() + delay > self.prepare_timeout {
+                return Err(GetMediaError::PreparationTimedOut(self.prepare_timeout));
             }
-        }?;
+            tokio::time::sleep(delay).await;
+            *attempt += 1;
+            let status = self
+                .get_page(status_url)
+                .await
+                .map_err(GetMediaError::RequestError)?
+                .text()
+                .await
+                .map_err(GetMediaError::RequestError)?;
+            let download_status = parse_download_status(&status).map_err(|err| {
+                GetMediaError::InvalidDownloadStatus(err.in_page(status_url, &status))
+            })?;
+            match download_status {
+                DownloadStatus::Ready(download_url) => {
+                    return Ok(download_url.unwrap_or_else(|| url.to_string()))
+                }
+                DownloadStatus::Preparing => {}
+                DownloadStatus::Failed(error) => {
+                    return Err(GetMediaError::PreparationFailed(error))
+                }
+            }
+        }
+    }
+
+    fn media_from_response(
+        &self,
+        url: String,
+        response: reqwest::Response,
+    ) -> Result<AsyncMediaDownload, GetMediaError> {
+        let content_disposition = response
+            .headers()
+            .get(reqwest::header::CONTENT_DISPOSITION)
+            .ok_or_else(|| {
+                GetMediaError::NoContentDisposition(format!("{:?}", &response.headers()))
+            })?;
         let capture = RE_CD
             .captures(content_disposition.as_bytes())
             .ok_or_else(|| {
@@ -109,19 +792,210 @@ impl Downloader {
         let filename = std::str::from_utf8(&capture[1])
             .map_err(|e| GetMediaError::InvalidFilename(capture[1].into(), e))?
             .to_string();
-        let content_length_h = response
-            .headers()
-            .get(reqwest::header::CONTENT_LENGTH)
-            .ok_or(GetMediaError::NoContentLength)?;
-        let content_length: u64 = content_length_h
-            .to_str()
-            .map_err(|_| GetMediaError::InvalidContentLength(content_length_h.as_bytes().into()))?
-            .parse()
-            .map_err(|_| GetMediaError::InvalidContentLength(content_length_h.as_bytes().into()))?;
-        Ok(ReqwestMediaDownload {
+        let content_length = get_content_length(&response)?;
+        let validator = get_validator(&response);
+        Ok(AsyncMediaDownload {
+            downloader: self.clone(),
+            url,
+            offset: 0,
             content_length,
             filename,
-            response,
+            validator,
+            body: self.body_stream(response),
         })
     }
 }
+
+/// A blocking wrapper around `AsyncDownloader`
+#[derive(Clone)]
+pub struct Downloader {
+    inner: AsyncDownloader,
+}
+
+impl Downloader {
+    pub fn new(
+        cookies: Vec<reqwest::header::HeaderValue>,
+        config: &DownloaderConfig,
+    ) -> Result<Self, DownloaderConfigError> {
+        Ok(Downloader {
+            inner: AsyncDownloader::new(cookies, config)?,
+        })
+    }
+
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        Downloader {
+            inner: self.inner.with_retry_policy(retry_policy),
+        }
+    }
+
+    pub fn with_rate_limits(self, rate_limits: &RateLimits) -> Self {
+        Downloader {
+            inner: self.inner.with_rate_limits(rate_limits),
+        }
+    }
+
+    pub fn base_url(&self) -> &reqwest::Url {
+        self.inner.base_url()
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.inner.is_replaying()
+    }
+
+    pub fn with_bandwidth_limit(self, bandwidth_limit: &BandwidthLimit) -> Self {
+        Downloader {
+            inner: self.inner.with_bandwidth_limit(bandwidth_limit),
+        }
+    }
+
+    pub fn get_page(&self, url: &str) -> reqwest::Result<String> {
+        RUNTIME.block_on(async { self.inner.get_page(url).await?.text().await })
+    }
+
+    pub fn post_api<T: Serialize + ?Sized>(&self, url: &str, json: &T) -> reqwest::Result<String> {
+        RUNTIME.block_on(async { self.inner.post_api(url, json).await?.text().await })
+    }
+
+    pub fn get_art(&self, art_id: u64, size: &ArtSize) -> reqwest::Result<Vec<u8>> {
+        RUNTIME.block_on(async {
+            let art = self.inner.get_art(art_id, size).await?.bytes().await?;
+            Ok(art.to_vec())
+        })
+    }
+
+    pub fn get_media(&self, url: &str) -> Result<ReqwestMediaDownload, GetMediaError> {
+        let inner = RUNTIME.block_on(self.inner.get_media(url))?;
+        Ok(ReqwestMediaDownload { inner })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::downloader::{
+        get_status_url, is_retryable_status, parse_content_range, parse_retry_after,
+        split_pem_certificates, Downloader, DownloaderConfig, GetMediaError, MediaDownload,
+        RetryPolicy,
+    };
+    use crate::mock_server::{Fault, MockServer};
+
+    fn preparing_downloader(server: &MockServer, prepare_timeout_ms: u64) -> Downloader {
+        let config = DownloaderConfig {
+            base_url: server.base_url.clone(),
+            prepare_timeout: std::time::Duration::from_millis(prepare_timeout_ms),
+            ..DownloaderConfig::default()
+        };
+        Downloader::new(vec![], &config)
+            .unwrap()
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 3,
+                base_delay: std::time::Duration::from_millis(1),
+                max_delay: std::time::Duration::from_millis(5),
+            })
+    }
+
+    #[test]
+    fn test_get_media_waits_until_prepared() {
+        let server = MockServer::start();
+        server.inject("/download/album", Fault::Preparing(3));
+        let downloader = preparing_downloader(&server, 10_000);
+        let url = server
+            .base_url
+            .join("download/album?enc=flac&id=1")
+            .unwrap();
+        let media_download = downloader.get_media(url.as_str()).unwrap();
+        assert_eq!(media_download.get_filename(), "Prepared Album.zip");
+    }
+
+    #[test]
+    fn test_get_media_gives_up_preparing() {
+        let server = MockServer::start();
+        server.inject("/download/album", Fault::Preparing(usize::MAX));
+        let downloader = preparing_downloader(&server, 50);
+        let url = server
+            .base_url
+            .join("download/album?enc=flac&id=1")
+            .unwrap();
+        assert!(matches!(
+            downloader.get_media(url.as_str()),
+            Err(GetMediaError::PreparationTimedOut(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range(b"bytes 100-199/200"), Some((100, 200)));
+        assert_eq!(parse_content_range(b"bytes 0-0/1"), Some((0, 1)));
+        assert_eq!(parse_content_range(b"bytes 100-199/*"), None);
+        assert_eq!(parse_content_range(b"items 1-2/3"), None);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = chrono::DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let seconds = |seconds| Some(std::time::Duration::new(seconds, 0));
+        assert_eq!(parse_retry_after("120", now), seconds(120));
+        assert_eq!(parse_retry_after(" 0 ", now), seconds(0));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now),
+            seconds(120)
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            seconds(0)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: std::time::Duration::new(1, 0),
+            max_delay: std::time::Duration::new(10, 0),
+        };
+        for (attempt, ceiling) in [(1, 1), (2, 2), (3, 4), (4, 8), (5, 10), (9, 10)] {
+            let delay = policy.backoff(attempt);
+            let ceiling = std::time::Duration::new(ceiling, 0);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn test_retryable_status() {
+        assert!(is_retryable_status(reqwest::StatusCode::BAD_GATEWAY));
+        assert!(is_retryable_status(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable_status(reqwest::StatusCode::UNAUTHORIZED));
+        assert!(!is_retryable_status(reqwest::StatusCode::FORBIDDEN));
+        assert!(!is_retryable_status(reqwest::StatusCode::NOT_FOUND));
+    }
+
+    #[test]
+    fn test_split_pem_certificates() {
+        let bundle = "# first\n-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+                      # second\n-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n\
+                      -----BEGIN CERTIFICATE-----\ntruncated\n";
+        assert_eq!(
+            split_pem_certificates(bundle),
+            vec![
+                "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----",
+                "-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----",
+            ]
+        );
+        assert!(split_pem_certificates("not a certificate").is_empty());
+    }
+
+    #[test]
+    fn test_get_status_url() {
+        assert_eq!(
+            get_status_url("https://popplers5.bandcamp.com/download/album?enc=flac&id=1&sig=x")
+                .as_deref(),
+            Some("https://popplers5.bandcamp.com/statdownload/album?enc=flac&id=1&sig=x&.vrs=1")
+        );
+        assert_eq!(
+            get_status_url("https://bandcamp.com/other/album?id=1"),
+            None
+        );
+    }
+}
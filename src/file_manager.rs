@@ -1,16 +1,66 @@
 use std::ffi::OsStr;
 
+use crate::manifest::*;
+use crate::parser::AlbumPageData;
 use crate::types::*;
 
+const EXTRAS_DIRECTORY: &str = "extras";
+const LYRICS_DIRECTORY: &str = "lyrics";
+
 lazy_static::lazy_static! {
     static ref RE_FORBIDDEN: regex::Regex = regex::Regex::new(r#"[/<>:|?*"\\]"#).unwrap();
 }
 
 pub struct FileManager {
     pub root_directory: std::path::PathBuf,
+    /// Held while updating a manifest, since several items can share one
+    manifest_lock: std::sync::Mutex<()>,
+    /// Albums and tracks being downloaded right now, see `claim`
+    claimed: std::sync::Mutex<std::collections::HashSet<std::path::PathBuf>>,
+}
+
+/// An album or track one worker is downloading, released when dropped
+pub struct Claim<'a> {
+    file_manager: &'a FileManager,
+    path: std::path::PathBuf,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.file_manager.claimed.lock().unwrap().remove(&self.path);
+    }
 }
 
 impl FileManager {
+    pub fn new(root_directory: std::path::PathBuf) -> Self {
+        FileManager {
+            root_directory,
+            manifest_lock: std::sync::Mutex::new(()),
+            claimed: std::sync::Mutex::new(std::collections::HashSet::new()),
+        }
+    }
+
+    /// Reserve the item for the calling worker, or None if another worker
+    /// is already downloading it, e.g. as a release of a bundle
+    pub fn claim(&self, collection_item: &CollectionItem) -> Option<Claim<'_>> {
+        let mut path = self.get_item_directory(collection_item);
+        if collection_item.itype == CollectionItemKind::Track {
+            // a track is told apart from the others in its directory by its title
+            path.push(
+                RE_FORBIDDEN
+                    .replace_all(&collection_item.title, "_")
+                    .as_ref(),
+            );
+        }
+        if !self.claimed.lock().unwrap().insert(path.clone()) {
+            return None;
+        }
+        Some(Claim {
+            file_manager: self,
+            path,
+        })
+    }
+
     fn get_album_directory(&self, artist: &str, title: &str) -> std::path::PathBuf {
         let cartist = RE_FORBIDDEN.replace_all(artist, "_");
         let mut path = self.root_directory.join(cartist.into_owned());
@@ -19,11 +69,23 @@ impl FileManager {
         path
     }
 
+    /// Tracks by the same artist share a directory, with its manifest and `.incomplete` marker
     fn get_track_directory(&self, artist: &str) -> std::path::PathBuf {
         let cartist = RE_FORBIDDEN.replace_all(artist, "_");
         self.root_directory.join(cartist.into_owned())
     }
 
+    fn get_item_directory(&self, collection_item: &CollectionItem) -> std::path::PathBuf {
+        match collection_item.itype {
+            CollectionItemKind::Album
+            | CollectionItemKind::Bundle
+            | CollectionItemKind::Package => {
+                self.get_album_directory(&collection_item.artist, &collection_item.title)
+            }
+            CollectionItemKind::Track => self.get_track_directory(&collection_item.artist),
+        }
+    }
+
     pub fn is_completed(&self, collection_item: &CollectionItem) -> Result<bool, std::io::Error> {
         match collection_item.itype {
             CollectionItemKind::Album => {
@@ -36,7 +98,10 @@ impl FileManager {
                 }
                 Ok(dir.read_dir()?.next().is_some())
             }
-            CollectionItemKind::Track => Ok(false),
+            // tracks, and the releases of bundles and merch, are checked one by one
+            CollectionItemKind::Track
+            | CollectionItemKind::Bundle
+            | CollectionItemKind::Package => Ok(false),
         }
     }
 
@@ -46,7 +111,9 @@ impl FileManager {
         filename: &str,
     ) -> Result<bool, std::io::Error> {
         match collection_item.itype {
-            CollectionItemKind::Album => self.is_completed(collection_item),
+            CollectionItemKind::Album
+            | CollectionItemKind::Bundle
+            | CollectionItemKind::Package => self.is_completed(collection_item),
             CollectionItemKind::Track => {
                 let dir = self
                     .get_track_directory(&collection_item.artist)
@@ -56,18 +123,180 @@ impl FileManager {
         }
     }
 
-    pub fn complete(&self, collection_item: &CollectionItem) -> Result<(), std::io::Error> {
-        let mut dir = match collection_item.itype {
-            CollectionItemKind::Album => {
-                self.get_album_directory(&collection_item.artist, &collection_item.title)
-            }
-            CollectionItemKind::Track => self.get_track_directory(&collection_item.artist),
-        };
+    /// Mark an item as complete, recording the files it consists of in the
+    /// manifest of its directory
+    pub fn complete(
+        &self,
+        collection_item: &CollectionItem,
+        files: &[std::path::PathBuf],
+    ) -> Result<(), std::io::Error> {
+        let mut dir = self.get_item_directory(collection_item);
+        let mut entries = vec![];
+        for file in files {
+            let name = file.strip_prefix(&dir).unwrap_or(file);
+            entries.push((
+                name.to_string_lossy().into_owned(),
+                ManifestEntry::from_file(file)?,
+            ));
+        }
+        {
+            let _guard = self.manifest_lock.lock().unwrap();
+            let mut manifest = Manifest::load(&dir)?;
+            manifest.files.extend(entries);
+            manifest.save(&dir)?;
+        }
         dir.push(".incomplete");
-        if dir.exists() {
-            std::fs::remove_file(dir)?;
+        // another item sharing the directory may have completed and removed it already
+        match std::fs::remove_file(dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// All directories (below the root) that have a manifest
+    pub fn get_manifest_directories(&self) -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+        let mut directories = vec![];
+        let mut pending = vec![self.root_directory.clone()];
+        while let Some(dir) = pending.pop() {
+            if dir.join(MANIFEST_FILENAME).exists() {
+                directories.push(dir.clone());
+            }
+            for entry in dir.read_dir()? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    pending.push(entry.path());
+                }
+            }
+        }
+        directories.sort();
+        Ok(directories)
+    }
+
+    /// Remove files that failed verification so that the next sync downloads
+    /// them again
+    pub fn invalidate(
+        &self,
+        directory: &std::path::Path,
+        failures: &[VerifyFailure],
+    ) -> Result<(), std::io::Error> {
+        std::fs::File::create(directory.join(".incomplete"))?;
+        let _guard = self.manifest_lock.lock().unwrap();
+        let mut manifest = Manifest::load(directory)?;
+        for failure in failures {
+            let path = directory.join(failure.filename());
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+            manifest.files.remove(failure.filename());
+        }
+        manifest.save(directory)
+    }
+
+    /// Where an item's cover art goes: the album directory, or next to the track
+    pub fn get_art_paths(&self, collection_item: &CollectionItem) -> Vec<std::path::PathBuf> {
+        let dir = self.get_item_directory(collection_item);
+        match collection_item.itype {
+            CollectionItemKind::Album => vec![dir.join("cover.jpg"), dir.join("folder.jpg")],
+            CollectionItemKind::Track => {
+                let ctitle = RE_FORBIDDEN.replace_all(&collection_item.title, "_");
+                vec![dir.join(format!("{}.jpg", ctitle))]
+            }
+            CollectionItemKind::Bundle | CollectionItemKind::Package => vec![],
         }
-        Ok(())
+    }
+
+    /// Write a file in one go, so that a crash never leaves half of it behind
+    pub fn save_file(&self, path: &std::path::Path, data: &[u8]) -> Result<(), std::io::Error> {
+        let mut tempname = std::ffi::OsString::from(".temporary.");
+        tempname.push(path.file_name().unwrap_or_default());
+        let temppath = path.with_file_name(tempname);
+        std::fs::write(&temppath, data)?;
+        std::fs::rename(temppath, path)
+    }
+
+    /// Write an album's lyrics, credits and about text next to its music,
+    /// unless they are already there, returning the files written
+    pub fn save_album_texts(
+        &self,
+        collection_item: &CollectionItem,
+        album: &AlbumPageData,
+    ) -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+        let dir = self.get_item_directory(collection_item);
+        let mut texts = vec![];
+        for track in album.tracks.iter() {
+            let lyrics = match &track.lyrics {
+                Some(lyrics) if !lyrics.trim().is_empty() => lyrics,
+                _ => continue,
+            };
+            let ctitle = RE_FORBIDDEN.replace_all(&track.title, "_");
+            let filename = match track.number {
+                Some(number) => format!("{:02} - {}.txt", number, ctitle),
+                None => format!("{}.txt", ctitle),
+            };
+            texts.push((dir.join(LYRICS_DIRECTORY).join(filename), lyrics));
+        }
+        if let Some(credits) = &album.credits {
+            texts.push((dir.join("credits.txt"), credits));
+        }
+        if let Some(about) = &album.about {
+            texts.push((dir.join("about.txt"), about));
+        }
+        let mut files = vec![];
+        for (path, text) in texts {
+            if path.exists() {
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            self.save_file(&path, text.as_bytes())?;
+            files.push(path);
+        }
+        Ok(files)
+    }
+
+    /// Where to download a bonus file of an album, which go in its `extras` folder
+    /// named after their title, so that they can be found again before downloading
+    pub fn get_extra_filepath(
+        &self,
+        collection_item: &CollectionItem,
+        title: &str,
+        filename: &std::path::Path,
+    ) -> Result<(std::path::PathBuf, std::path::PathBuf), std::io::Error> {
+        let dir = self.get_item_directory(collection_item);
+        let extras = dir.join(EXTRAS_DIRECTORY);
+        if !extras.exists() {
+            std::fs::create_dir_all(&extras)?;
+        }
+        std::fs::File::create(dir.join(".incomplete"))?;
+        let mut name = RE_FORBIDDEN.replace_all(title, "_").into_owned();
+        if let Some(extension) = filename.extension() {
+            name.push('.');
+            name.push_str(&extension.to_string_lossy());
+        }
+        Ok((
+            extras.join(format!(".temporary.{}", name)),
+            extras.join(name),
+        ))
+    }
+
+    /// The bonus file with this title that an earlier run saved, if any
+    pub fn find_extra(
+        &self,
+        collection_item: &CollectionItem,
+        title: &str,
+    ) -> Option<std::path::PathBuf> {
+        let extras = self
+            .get_item_directory(collection_item)
+            .join(EXTRAS_DIRECTORY);
+        let name = RE_FORBIDDEN.replace_all(title, "_");
+        let name: &OsStr = name.as_ref().as_ref();
+        extras
+            .read_dir()
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .find(|path| path.file_name() == Some(name) || path.file_stem() == Some(name))
     }
 
     pub fn get_filepath(
@@ -75,12 +304,7 @@ impl FileManager {
         collection_item: &CollectionItem,
         filename: &std::path::Path,
     ) -> Result<(std::path::PathBuf, std::path::PathBuf), std::io::Error> {
-        let dir = match collection_item.itype {
-            CollectionItemKind::Album => {
-                self.get_album_directory(&collection_item.artist, &collection_item.title)
-            }
-            CollectionItemKind::Track => self.get_track_directory(&collection_item.artist),
-        };
+        let dir = self.get_item_directory(collection_item);
         if !dir.exists() {
             std::fs::create_dir_all(&dir)?;
         }
@@ -1,27 +1,176 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::Serialize;
 use std::io::Write;
 
+mod console;
 mod downloader;
 mod file_manager;
+mod manifest;
+#[cfg(test)]
+mod mock_server;
 mod parser;
+mod progress;
+mod rate_limiter;
+mod recorder;
 mod types;
+mod zip_stream;
 
+use crate::console::*;
 use crate::downloader::*;
 use crate::file_manager::*;
+use crate::manifest::*;
 use crate::parser::*;
+use crate::progress::*;
 use crate::types::*;
 
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Args {
-    #[arg(short='f', long="format", default_value_t = Encoding::Flac)]
+    #[arg(short='f', long="format", default_value_t = Encoding::Flac, global = true)]
     format: Encoding,
-    #[arg(short = 'u', long = "username")]
+    #[arg(short = 'u', long = "username", global = true)]
     username: Option<String>,
+    /// How many times to attempt each request before giving up
+    #[arg(long = "retries", default_value_t = RetryPolicy::default().max_attempts, global = true)]
+    retries: u32,
+    /// Maximum page and API requests per second (0 for no limit)
+    #[arg(long = "request-rate", default_value_t = RateLimits::default().page_requests, global = true)]
+    request_rate: f64,
+    /// Maximum media downloads started per second (0 for no limit)
+    #[arg(long = "media-rate", default_value_t = RateLimits::default().media_requests, global = true)]
+    media_rate: f64,
+    /// How many collection items to download at the same time
+    #[arg(short = 'j', long = "jobs", default_value_t = 1, global = true)]
+    jobs: usize,
+    /// The size of cover art to save with each item
+    #[arg(long = "cover-size", value_enum, default_value_t = ArtSize::Large, global = true)]
+    cover_size: ArtSize,
+    /// Don't save cover art
+    #[arg(long = "no-cover-art", global = true)]
+    no_cover_art: bool,
+    /// Don't check extracted albums against the track listing on their album page
+    #[arg(long = "no-track-check", global = true)]
+    no_track_check: bool,
+    /// Also download the items hidden from your public collection
+    #[arg(long = "include-hidden", global = true)]
+    include_hidden: bool,
+    /// Don't download bonus files such as PDFs and videos into each album's `extras` folder
+    #[arg(long = "no-extras", global = true)]
+    no_extras: bool,
+    /// Save the lyrics, credits and about text of albums as text files next to their music
+    #[arg(long = "save-texts", global = true)]
+    save_texts: bool,
+    /// Maximum media download speed in bytes per second, shared by all jobs (e.g. 500K or 5M)
+    #[arg(long = "limit-rate", value_parser = parse_byte_rate, global = true)]
+    limit_rate: Option<f64>,
+    /// Hours of the day during which `--limit-rate` is lifted (e.g. 22-6)
+    #[arg(long = "full-speed-hours", requires = "limit_rate", global = true)]
+    full_speed_hours: Option<rate_limiter::DailyWindow>,
+    /// Send all requests through this HTTP or SOCKS5 proxy (e.g. socks5h://localhost:1080)
+    #[arg(long = "proxy", env = "BANDCAMPER_PROXY", global = true)]
+    proxy: Option<String>,
+    /// A PEM file of extra certificates to trust, e.g. for a TLS-intercepting proxy
+    #[arg(
+        long = "root-certificate",
+        env = "BANDCAMPER_ROOT_CERTIFICATES",
+        value_delimiter = ',',
+        global = true
+    )]
+    root_certificates: Vec<std::path::PathBuf>,
+    /// The local IP address to send requests from
+    #[arg(
+        long = "local-address",
+        env = "BANDCAMPER_LOCAL_ADDRESS",
+        global = true
+    )]
+    local_address: Option<std::net::IpAddr>,
+    /// Timeout in seconds for page and API requests
+    #[arg(long = "timeout", env = "BANDCAMPER_TIMEOUT", default_value_t = DownloaderConfig::default().timeout.as_secs(), global = true)]
+    timeout: u64,
+    /// Timeout in seconds for each media download, including cover art
+    #[arg(long = "media-timeout", env = "BANDCAMPER_MEDIA_TIMEOUT", default_value_t = DownloaderConfig::default().media_timeout.as_secs(), global = true)]
+    media_timeout: u64,
+    /// How many seconds to wait for Bandcamp to prepare a download before giving up
+    #[arg(long = "prepare-timeout", env = "BANDCAMPER_PREPARE_TIMEOUT", default_value_t = DownloaderConfig::default().prepare_timeout.as_secs(), global = true)]
+    prepare_timeout: u64,
+    /// Where to find Bandcamp, for testing against a stand-in server
+    #[arg(long = "base-url", env = "BANDCAMPER_BASE_URL", default_value_t = DownloaderConfig::default().base_url, hide = true, global = true)]
+    base_url: reqwest::Url,
+    /// Save every request and response to this folder, with cookies removed
+    #[arg(
+        long = "record",
+        value_name = "DIR",
+        conflicts_with = "replay",
+        global = true
+    )]
+    record: Option<std::path::PathBuf>,
+    /// Serve requests from a folder made with `--record` instead of contacting Bandcamp
+    #[arg(long = "replay", value_name = "DIR", global = true)]
+    replay: Option<std::path::PathBuf>,
+    /// Where to save the data of pages that could not be read, for bug reports
+    /// [default: bandcamper-diagnostics in the temporary folder]
+    #[arg(
+        long = "diagnostics-dir",
+        value_name = "DIR",
+        env = "BANDCAMPER_DIAGNOSTICS_DIR",
+        global = true
+    )]
+    diagnostics_dir: Option<std::path::PathBuf>,
+    /// The User-Agent header sent with every request
+    #[arg(long = "user-agent", env = "BANDCAMPER_USER_AGENT", default_value_t = DownloaderConfig::default().user_agent, global = true)]
+    user_agent: String,
 
     /// The folder in which to save the music
     path: Option<std::ffi::OsString>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check downloaded music against the checksums recorded when it was downloaded
+    Verify {
+        /// Re-download items that fail verification without asking first
+        #[arg(short = 'y', long = "yes")]
+        yes: bool,
+
+        /// The folder in which the music was saved
+        path: Option<std::ffi::OsString>,
+    },
+    /// List the albums and tracks on your wishlist
+    Wishlist {
+        /// Write the wishlist as JSON or CSV instead of listing it
+        #[arg(long = "export", value_enum)]
+        export: Option<ExportFormat>,
+
+        /// The file to export to, instead of standard output
+        #[arg(short = 'o', long = "output", requires = "export")]
+        output: Option<std::path::PathBuf>,
+    },
+}
+
+/// Parse a number of bytes with an optional K, M or G suffix
+fn parse_byte_rate(text: &str) -> Result<f64, String> {
+    let (number, multiplier) = match text.trim().char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let multiplier = match c.to_ascii_lowercase() {
+                'k' => 1024f64,
+                'm' => 1024f64 * 1024f64,
+                'g' => 1024f64 * 1024f64 * 1024f64,
+                _ => return Err(format!("unknown unit {:?}", c)),
+            };
+            (&text.trim()[..i], multiplier)
+        }
+        _ => (text.trim(), 1f64),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid number {:?}", number))?;
+    if number < 0f64 {
+        return Err("rate must not be negative".to_string());
+    }
+    Ok(number * multiplier)
 }
 
 fn pick_format<'a>(
@@ -42,6 +191,23 @@ fn pick_format<'a>(
 enum HandleDownloadResponseError {
     BadZip(zip::result::ZipError),
     BadIO(std::io::Error),
+    BadMedia(GetMediaError),
+    BadTransfer(std::io::Error),
+    MissingTracks(Vec<String>),
+}
+
+impl std::fmt::Display for HandleDownloadResponseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HandleDownloadResponseError::BadZip(e) => write!(f, "invalid archive: {}", e),
+            HandleDownloadResponseError::BadIO(e) => write!(f, "could not write files: {}", e),
+            HandleDownloadResponseError::BadMedia(e) => write!(f, "{}", e),
+            HandleDownloadResponseError::BadTransfer(e) => write!(f, "download failed: {}", e),
+            HandleDownloadResponseError::MissingTracks(tracks) => {
+                write!(f, "archive is missing tracks: {}", tracks.join(", "))
+            }
+        }
+    }
 }
 
 impl From<std::io::Error> for HandleDownloadResponseError {
@@ -50,49 +216,297 @@ impl From<std::io::Error> for HandleDownloadResponseError {
     }
 }
 
-fn handle_download_response<D: MediaDownload>(
+/// Where the validator of a partial download is kept, next to the download itself
+fn validator_path(tempfile: &std::path::Path) -> std::path::PathBuf {
+    let mut name = tempfile.as_os_str().to_os_string();
+    name.push(".validator");
+    name.into()
+}
+
+/// Remove a partial download along with its validator
+fn remove_tempfile(tempfile: &std::path::Path) -> Result<(), std::io::Error> {
+    let _ = std::fs::remove_file(validator_path(tempfile));
+    std::fs::remove_file(tempfile)
+}
+
+/// Start a download over in `tempfile`, remembering which version of the file it is
+fn create_tempfile<D: MediaDownload>(
+    media_download: &D,
+    tempfile: &std::path::Path,
+) -> Result<std::fs::File, std::io::Error> {
+    match media_download.get_validator() {
+        Some(validator) => std::fs::write(validator_path(tempfile), validator)?,
+        None => {
+            let _ = std::fs::remove_file(validator_path(tempfile));
+        }
+    }
+    std::fs::File::create(tempfile)
+}
+
+/// Move a finished download into place
+fn rename_tempfile(
+    tempfile: &std::path::Path,
+    realfile: &std::path::Path,
+) -> Result<(), std::io::Error> {
+    let _ = std::fs::remove_file(validator_path(tempfile));
+    std::fs::rename(tempfile, realfile)
+}
+
+fn download_to_tempfile<D: MediaDownload>(
+    console: &ItemConsole,
+    media_download: &mut D,
+    observer: &mut dyn ProgressObserver,
+    tempfile: &std::path::Path,
+) -> Result<(), HandleDownloadResponseError> {
+    let content_length = media_download.get_content_length();
+    let partial_length = match std::fs::metadata(tempfile) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    // without a validator there is no telling whether the partial data is of the same file
+    let validator = std::fs::read_to_string(validator_path(tempfile)).ok();
+    let unchanged = validator.is_some() && validator.as_deref() == media_download.get_validator();
+    if partial_length > 0 && partial_length == content_length && unchanged {
+        console.println("  Using previously downloaded data");
+        return Ok(());
+    }
+    let offset = if partial_length > 0 && partial_length < content_length && validator.is_some() {
+        media_download
+            .resume_from(partial_length, validator.as_deref())
+            .map_err(HandleDownloadResponseError::BadMedia)?
+    } else {
+        0
+    };
+    let mut tmp = if offset > 0 {
+        console.println(format!("  Resuming download at byte {}", offset));
+        std::fs::OpenOptions::new().append(true).open(tempfile)?
+    } else {
+        create_tempfile(media_download, tempfile)?
+    };
+    media_download
+        .save(&mut tmp, observer)
+        .map_err(HandleDownloadResponseError::BadTransfer)?;
+    tmp.flush()?;
+    Ok(())
+}
+
+/// Extract archive members into their temporary files as the archive arrives,
+/// recording every temporary file created so the caller can clean them up
+fn stream_zip_members<R: std::io::Read>(
     file_manager: &FileManager,
     item: &CollectionItem,
-    media_download: &mut D,
+    reader: &mut R,
+    extracted: &mut Vec<(std::path::PathBuf, std::path::PathBuf)>,
 ) -> Result<(), HandleDownloadResponseError> {
-    if file_manager
-        .is_completed_file(item, media_download.get_filename())
-        .unwrap()
+    let mut members = vec![];
+    while let Some(mut file) =
+        zip::read::read_zipfile_from_stream(reader).map_err(HandleDownloadResponseError::BadZip)?
     {
-        println!("  Skipping download (already completed)");
+        members.push(zip_stream::ZipEntry::from_zipfile(&file));
+        if !file.is_file() {
+            continue;
+        }
+        let name = file
+            .enclosed_name()
+            .ok_or(HandleDownloadResponseError::BadZip(
+                zip::result::ZipError::UnsupportedArchive("invalid filename in archive"),
+            ))?
+            .to_path_buf();
+        let (tempfile, realfile) = file_manager.get_filepath(item, &name)?;
+        let mut fsfile = std::fs::File::create(&tempfile)?;
+        extracted.push((tempfile, realfile));
+        std::io::copy(&mut file, &mut fsfile)?;
+    }
+    let central_directory =
+        zip_stream::read_central_directory(reader).map_err(HandleDownloadResponseError::BadZip)?;
+    zip_stream::validate(&members, &central_directory).map_err(HandleDownloadResponseError::BadZip)
+}
+
+/// Extract an archive as it arrives, keeping a copy of it in `ziptemp`
+/// so that a failed transfer can be resumed from there
+fn stream_zip<D: MediaDownload>(
+    file_manager: &FileManager,
+    item: &CollectionItem,
+    media_download: &mut D,
+    observer: &mut dyn ProgressObserver,
+    ziptemp: &std::path::Path,
+) -> Result<Vec<std::path::PathBuf>, HandleDownloadResponseError> {
+    let content_length = media_download.get_content_length();
+    let copy = create_tempfile(media_download, ziptemp)?;
+    let mut extracted = vec![];
+    let mut body = zip_stream::CopyingReader::new(media_download.reader(), copy);
+    let mut reader = ProgressReader::new(&mut body, observer, 0, content_length);
+    if let Err(err) = stream_zip_members(file_manager, item, &mut reader, &mut extracted) {
+        for (tempfile, _) in extracted {
+            let _ = std::fs::remove_file(tempfile);
+        }
+        return Err(err);
+    }
+    reader.finish();
+    remove_tempfile(ziptemp)?;
+    let mut files = vec![];
+    for (tempfile, realfile) in extracted {
+        if (realfile).exists() {
+            std::fs::remove_file(&realfile)?;
+        }
+        std::fs::rename(tempfile, &realfile)?;
+        files.push(realfile);
+    }
+    Ok(files)
+}
+
+lazy_static::lazy_static! {
+    /// Track numbers in the names Bandcamp gives files, e.g. "Artist - Album - 01 Title.flac"
+    static ref RE_TRACK_NUMBER: regex::Regex = regex::Regex::new(r#"(?:^|\s-\s)([0-9]{1,3})\s"#).unwrap();
+    static ref RE_NOT_FILENAME: regex::Regex = regex::Regex::new(r#"[^A-Za-z0-9.-]+"#).unwrap();
+}
+
+fn normalize_title(title: &str) -> String {
+    title
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The downloadable tracks of an album's listing that no extracted file looks like
+fn find_missing_tracks<'a>(
+    tracks: &'a [TrackInfo],
+    files: &[std::path::PathBuf],
+) -> Vec<&'a TrackInfo> {
+    let stems: Vec<String> = files
+        .iter()
+        .filter_map(|file| file.file_stem())
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .collect();
+    let numbers: std::collections::HashSet<u32> = stems
+        .iter()
+        .filter_map(|stem| RE_TRACK_NUMBER.captures(stem))
+        .filter_map(|capture| capture[1].parse().ok())
+        .collect();
+    let titles: Vec<String> = stems.iter().map(|stem| normalize_title(stem)).collect();
+    tracks
+        .iter()
+        .filter(|track| track.is_downloadable != Some(false))
+        .filter(|track| {
+            let title = normalize_title(&track.title);
+            let by_number = track.number.is_some_and(|number| numbers.contains(&number));
+            let by_title = !title.is_empty() && titles.iter().any(|stem| stem.contains(&title));
+            !by_number && !by_title
+        })
+        .collect()
+}
+
+/// Fail if any track of the album's listing was not extracted,
+/// which leaves the item marked incomplete
+fn check_tracks(
+    tracks: Option<&[TrackInfo]>,
+    files: &[std::path::PathBuf],
+) -> Result<(), HandleDownloadResponseError> {
+    let missing = match tracks {
+        Some(tracks) => find_missing_tracks(tracks, files),
+        None => return Ok(()),
+    };
+    if missing.is_empty() {
         return Ok(());
     }
+    Err(HandleDownloadResponseError::MissingTracks(
+        missing
+            .iter()
+            .map(|track| match track.number {
+                Some(number) => format!("{:02} {}", number, track.title),
+                None => track.title.clone(),
+            })
+            .collect(),
+    ))
+}
+
+/// Extract a downloaded archive next to it
+fn extract_zip(
+    file_manager: &FileManager,
+    item: &CollectionItem,
+    ziptemp: &std::path::Path,
+) -> Result<Vec<std::path::PathBuf>, HandleDownloadResponseError> {
+    let file = std::fs::File::open(ziptemp).map_err(HandleDownloadResponseError::BadIO)?;
+    let mut ziparchive = zip::ZipArchive::new(file).map_err(HandleDownloadResponseError::BadZip)?;
+    let mut files = vec![];
+    for i in 0..ziparchive.len() {
+        let mut file = ziparchive
+            .by_index(i)
+            .map_err(HandleDownloadResponseError::BadZip)?;
+        if !file.is_file() {
+            continue;
+        }
+        let name = file
+            .enclosed_name()
+            .ok_or(HandleDownloadResponseError::BadZip(
+                zip::result::ZipError::UnsupportedArchive("invalid filename in archive"),
+            ))?;
+        let (tempfile, realfile) = file_manager.get_filepath(item, name)?;
+        if (realfile).exists() {
+            std::fs::remove_file(&realfile)?;
+        }
+        let mut fsfile = std::fs::File::create(&tempfile)?;
+        std::io::copy(&mut file, &mut fsfile)?;
+        std::fs::rename(tempfile, &realfile)?;
+        files.push(realfile);
+    }
+    Ok(files)
+}
+
+fn handle_download_response<D: MediaDownload>(
+    console: &ItemConsole,
+    file_manager: &FileManager,
+    item: &CollectionItem,
+    media_download: &mut D,
+    observer: &mut dyn ProgressObserver,
+    tracks: Option<&[TrackInfo]>,
+) -> Result<ItemOutcome, HandleDownloadResponseError> {
+    if file_manager.is_completed_file(item, media_download.get_filename())? {
+        console.println("  Skipping download (already completed)");
+        return Ok(ItemOutcome::Skipped);
+    }
+    let mut files = vec![];
     if media_download.get_filename().ends_with(".zip") {
         //let tempfile = file_manager.get_tempfilepath(item).unwrap();
         let filename: std::path::PathBuf = media_download.get_filename().to_string().into();
-        let (ziptemp, _) = file_manager.get_filepath(item, &filename).unwrap();
-        let mut tmp = std::fs::File::create(&ziptemp)?;
-        media_download.save(&mut tmp).unwrap();
-        tmp.flush()?;
-        let file = std::fs::File::open(&ziptemp).map_err(HandleDownloadResponseError::BadIO)?;
-        let mut ziparchive =
-            zip::ZipArchive::new(file).map_err(HandleDownloadResponseError::BadZip)?;
-        for i in 0..ziparchive.len() {
-            let mut file = ziparchive
-                .by_index(i)
-                .map_err(HandleDownloadResponseError::BadZip)?;
-            if !file.is_file() {
-                continue;
+        let (ziptemp, _) = file_manager.get_filepath(item, &filename)?;
+        // a partial archive from an earlier run is worth resuming instead
+        if !ziptemp.exists() {
+            match stream_zip(file_manager, item, media_download, observer, &ziptemp) {
+                Ok(files) => {
+                    check_tracks(tracks, &files)?;
+                    file_manager.complete(item, &files)?;
+                    return Ok(ItemOutcome::Downloaded);
+                }
+                // only an archive that can't be streamed is worth downloading in full,
+                // a failed transfer resumes from the copy kept so far on the next run
+                Err(HandleDownloadResponseError::BadZip(err))
+                    if !matches!(err, zip::result::ZipError::Io(_)) =>
+                {
+                    console.println(format!(
+                        "  Could not extract the archive while downloading ({}), downloading it in full",
+                        err
+                    ));
+                    remove_tempfile(&ziptemp)?;
+                    media_download
+                        .resume_from(0, None)
+                        .map_err(HandleDownloadResponseError::BadMedia)?;
+                }
+                Err(err) => return Err(err),
             }
-            let name = file
-                .enclosed_name()
-                .ok_or(HandleDownloadResponseError::BadZip(
-                    zip::result::ZipError::UnsupportedArchive("invalid filename in archive"),
-                ))?;
-            let (tempfile, realfile) = file_manager.get_filepath(item, name)?;
-            if (realfile).exists() {
-                std::fs::remove_file(&realfile)?;
-            }
-            let mut fsfile = std::fs::File::create(&tempfile)?;
-            std::io::copy(&mut file, &mut fsfile)?;
-            std::fs::rename(tempfile, realfile)?;
         }
-        std::fs::remove_file(ziptemp)?;
+        download_to_tempfile(console, media_download, observer, &ziptemp)?;
+        files = match extract_zip(file_manager, item, &ziptemp) {
+            Err(err @ HandleDownloadResponseError::BadZip(_)) => {
+                // resuming a corrupt archive would only fail the same way again
+                remove_tempfile(&ziptemp)?;
+                return Err(err);
+            }
+            result => result?,
+        };
+        remove_tempfile(&ziptemp)?;
+        check_tracks(tracks, &files)?;
     } else {
         let mut filepath = std::path::PathBuf::new();
         filepath.set_file_name(media_download.get_filename());
@@ -100,17 +514,16 @@ fn handle_download_response<D: MediaDownload>(
         if (realfile).exists() {
             std::fs::remove_file(&realfile)?;
         }
-        let mut tmp = std::fs::File::create(&tempfile).unwrap();
-        media_download.save(&mut tmp).unwrap();
-        tmp.flush().unwrap();
+        download_to_tempfile(console, media_download, observer, &tempfile)?;
         // yes, we remove twice
         if (realfile).exists() {
             std::fs::remove_file(&realfile)?;
         }
-        std::fs::rename(tempfile, &realfile)?;
+        rename_tempfile(&tempfile, &realfile)?;
+        files.push(realfile);
     }
-    file_manager.complete(item)?;
-    Ok(())
+    file_manager.complete(item, &files)?;
+    Ok(ItemOutcome::Downloaded)
 }
 
 #[derive(Debug, Serialize)]
@@ -120,46 +533,105 @@ struct CollectionItemsRequestBody {
     count: u64,
 }
 
-fn load_bandcamp_cookies() -> Vec<(String, String, Downloader)> {
-    let cookies_results: Vec<bench_scraper::KnownBrowserCookies> =
-        bench_scraper::find_cookies().unwrap();
-    let mut bandcamp_cookies: Vec<Vec<bench_scraper::Cookie>> = cookies_results
-        .into_iter()
-        .map(|kbcookies| {
-            kbcookies
-                .cookies
-                .into_iter()
-                .filter(|c| c.host.ends_with("bandcamp.com"))
-                .collect()
-        })
-        .filter(|v: &Vec<bench_scraper::Cookie>| !v.is_empty())
-        .collect();
-    bandcamp_cookies.sort_by_key(|is| is.iter().map(|i| i.last_accessed).max());
-    bandcamp_cookies
-        .into_iter()
-        .filter_map(|cookies| {
-            let header_values: Vec<reqwest::header::HeaderValue> = cookies
-                .iter()
-                .map(|i| {
-                    let cookie_string = format!("{}={}; Domain=bandcamp.com", i.name, i.value);
-                    reqwest::header::HeaderValue::from_str(&cookie_string).unwrap()
-                })
-                .collect();
-            let downloader = Downloader::new(header_values).unwrap();
-            let home_page = downloader
-                .get_page("https://bandcamp.com")
-                .unwrap()
-                .text()
-                .unwrap();
-            parse_home_page(&home_page)
-                .ok()
-                .map(|(a, b)| (a, b, downloader))
-        })
-        .collect()
+/// Why the logins found in the browsers could not be checked
+#[derive(Debug)]
+enum LoginError {
+    BadSettings(DownloaderConfigError),
+    BadRequest(reqwest::Error),
+}
+
+impl std::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoginError::BadSettings(e) => write!(f, "invalid network settings: {}", e),
+            LoginError::BadRequest(e) => write!(f, "could not reach Bandcamp: {}", e),
+        }
+    }
+}
+
+impl From<DownloaderConfigError> for LoginError {
+    fn from(err: DownloaderConfigError) -> Self {
+        LoginError::BadSettings(err)
+    }
 }
 
-fn get_collection_link(username: Option<String>) -> Option<(String, Downloader)> {
-    let cookies = load_bandcamp_cookies();
+impl From<reqwest::Error> for LoginError {
+    fn from(err: reqwest::Error) -> Self {
+        LoginError::BadRequest(err)
+    }
+}
+
+/// A fan logged into Bandcamp: their username, collection link, and a downloader with their cookies
+type Login = (String, String, Downloader);
+
+/// The logins found in the browsers, along with why the home page could not be read
+/// with the last of the cookies that gave none
+fn load_bandcamp_cookies(
+    config: &DownloaderConfig,
+    retry_policy: &RetryPolicy,
+    rate_limits: &RateLimits,
+) -> Result<(Vec<Login>, Option<ParsePageError>), LoginError> {
+    let bandcamp_cookies: Vec<Vec<bench_scraper::Cookie>> = if config
+        .recorder
+        .as_ref()
+        .is_some_and(|recorder| recorder.is_replaying())
+    {
+        // the recording already holds the responses for whoever was logged in
+        vec![vec![]]
+    } else {
+        let cookies_results: Vec<bench_scraper::KnownBrowserCookies> =
+            bench_scraper::find_cookies().unwrap();
+        let mut bandcamp_cookies: Vec<Vec<bench_scraper::Cookie>> = cookies_results
+            .into_iter()
+            .map(|kbcookies| {
+                kbcookies
+                    .cookies
+                    .into_iter()
+                    .filter(|c| c.host.ends_with("bandcamp.com"))
+                    .collect()
+            })
+            .filter(|v: &Vec<bench_scraper::Cookie>| !v.is_empty())
+            .collect();
+        bandcamp_cookies.sort_by_key(|is| is.iter().map(|i| i.last_accessed).max());
+        bandcamp_cookies
+    };
+    let mut logins = vec![];
+    let mut page_error = None;
+    for cookies in bandcamp_cookies {
+        let header_values: Vec<reqwest::header::HeaderValue> = cookies
+            .iter()
+            .map(|i| {
+                let cookie_string = format!("{}={}; Domain=bandcamp.com", i.name, i.value);
+                reqwest::header::HeaderValue::from_str(&cookie_string).unwrap()
+            })
+            .collect();
+        let downloader = Downloader::new(header_values, config)?
+            .with_retry_policy(retry_policy.clone())
+            .with_rate_limits(rate_limits);
+        let url = downloader.base_url().to_string();
+        let home_page = downloader.get_page(&url)?;
+        match parse_home_page(&home_page) {
+            Ok((a, b)) => logins.push((a, b, downloader)),
+            Err(err) => page_error = Some(err.in_page(&url, &home_page)),
+        }
+    }
+    Ok((logins, page_error))
+}
+
+fn get_collection_link(
+    username: Option<String>,
+    config: &DownloaderConfig,
+    retry_policy: &RetryPolicy,
+    rate_limits: &RateLimits,
+    diagnostics_directory: &std::path::Path,
+) -> Option<(String, Downloader)> {
+    let (cookies, page_error) = match load_bandcamp_cookies(config, retry_policy, rate_limits) {
+        Ok(logins) => logins,
+        Err(err) => {
+            println!("Could not check your Bandcamp login: {}", err);
+            return None;
+        }
+    };
     match (username, cookies.as_slice()) {
         (_, []) => {
             println!("You are not logged into Bandcamp through your web browser.");
@@ -167,6 +639,13 @@ fn get_collection_link(username: Option<String>) -> Option<(String, Downloader)>
             println!(
                 "I support most common browsers (Chrome/Chromium, Edge, Firefox, Safari, ...)"
             );
+            // a login can also go unnoticed because Bandcamp changed its pages
+            if let Some(err) = page_error {
+                println!("The Bandcamp home page could not be read: {}", err);
+                if let Some(message) = save_diagnostics(Some(diagnostics_directory), &err) {
+                    println!("{}", message);
+                }
+            }
             None
         }
         (Some(usr), [(u, c, d)]) => {
@@ -209,166 +688,1315 @@ fn get_collection_link(username: Option<String>) -> Option<(String, Downloader)>
     }
 }
 
-fn process_collection_link(downloader: &Downloader, collection_link: &str) -> Vec<CollectionItem> {
-    let collection_page = downloader
-        .get_page(collection_link)
-        .unwrap()
-        .text()
-        .unwrap();
-    let collection_page_data = parse_collection_page(&collection_page).unwrap();
-    let fan_id = collection_page_data.fan_id;
-    let mut all_collection_items: Vec<CollectionItem> = collection_page_data.collection_items;
-    let mut older_than_token = collection_page_data.last_token;
-    let mut more_available = true;
+/// Send every item in the collection to `items` as soon as its page has been fetched,
+/// so that downloads can start while the rest of the collection is still paginated
+fn process_collection_link(
+    downloader: &Downloader,
+    collection_link: &str,
+    include_hidden: bool,
+    items: std::sync::mpsc::Sender<QueuedItem>,
+) -> Result<(), ListingError> {
+    let collection_page = downloader.get_page(collection_link)?;
+    let collection_page_data =
+        parse_listing(collection_link, &collection_page, parse_collection_page)?;
+    process_collection_items(
+        downloader,
+        collection_page_data,
+        "api/fancollection/1/collection_items",
+        &items,
+    )?;
+    if include_hidden {
+        let hidden_page_data =
+            parse_listing(collection_link, &collection_page, parse_hidden_items_page)?;
+        process_collection_items(
+            downloader,
+            hidden_page_data,
+            "api/fancollection/1/hidden_items",
+            &items,
+        )?;
+    }
+    Ok(())
+}
+
+/// Send the items of the first page to `items`, then those of every following page of `endpoint`
+fn process_collection_items(
+    downloader: &Downloader,
+    page_data: CollectionPageData,
+    endpoint: &str,
+    items: &std::sync::mpsc::Sender<QueuedItem>,
+) -> Result<(), ListingError> {
+    for item in page_data.collection_items {
+        send_item(downloader, item, items);
+    }
+    fetch_following_pages(
+        downloader,
+        endpoint,
+        page_data.fan_id,
+        page_data.last_token,
+        parse_collection_json,
+        |page| {
+            for item in page {
+                send_item(downloader, item, items);
+            }
+        },
+    )
+}
+
+/// Fetch the pages of one of the fan's listings that follow the page ending at `last_token`
+/// from `endpoint`, handing the items of each to `on_page` as it arrives
+fn fetch_following_pages<T>(
+    downloader: &Downloader,
+    endpoint: &str,
+    fan_id: u64,
+    last_token: String,
+    parse: fn(&str) -> Result<ListingJSON<T>, serde_json::Error>,
+    mut on_page: impl FnMut(Vec<T>),
+) -> Result<(), ListingError> {
+    let url = downloader.base_url().join(endpoint).unwrap();
+    // a page without a token has nothing more to fetch
+    let mut more_available = !last_token.is_empty();
+    let mut older_than_token = last_token;
     while more_available {
         let body = CollectionItemsRequestBody {
             fan_id,
             older_than_token,
             count: 20,
         };
-        let collection_json = downloader
-            .post_api(
-                "https://bandcamp.com/api/fancollection/1/collection_items",
-                &body,
-            )
-            .unwrap()
-            .text()
-            .unwrap();
-        let mut collection_data = parse_collection_json(&collection_json).unwrap();
-        all_collection_items.append(&mut collection_data.collection_items);
-        older_than_token = collection_data.last_token;
-        more_available = collection_data.more_available;
+        let listing_json = downloader.post_api(url.as_str(), &body)?;
+        let listing = parse_listing(url.as_str(), &listing_json, |json| Ok(parse(json)?))?;
+        on_page(listing.items);
+        older_than_token = listing.last_token;
+        more_available = listing.more_available;
     }
-    all_collection_items
+    Ok(())
 }
 
-fn main_impl() {
-    let settings = Args::parse();
-    let format_preferences = vec![settings.format];
-    let root_directory = match settings.path {
-        Some(p) => p.into(),
-        None => {
-            println!("No download folder was specified when starting the program.");
-            println!("Please select a folder in which you want to download your music.");
-            let folder = rfd::FileDialog::new()
-                .set_title("Bandcamp Downloads Folder")
-                .pick_folder();
-            folder.expect("No folder selected")
+/// Why the collection or wishlist could not be listed in full
+#[derive(Debug)]
+enum ListingError {
+    BadRequest(reqwest::Error),
+    BadPage(ParsePageError),
+}
+
+impl std::fmt::Display for ListingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ListingError::BadRequest(e) => write!(f, "request failed: {}", e),
+            ListingError::BadPage(e) => write!(f, "could not read Bandcamp's data: {}", e),
         }
-    };
-    let file_manager = FileManager { root_directory };
-    if !file_manager.root_directory.exists() {
-        std::fs::create_dir(&file_manager.root_directory).unwrap();
     }
-    println!("Scanning for Bandcamp collection data...");
-    let (collection_link, downloader) = get_collection_link(settings.username).unwrap();
-    let collection_items = process_collection_link(&downloader, &collection_link);
-    for item in collection_items.iter() {
-        println!(
-            "Processing item: {:?} \"{}\" by \"{}\"",
-            item.itype, item.title, item.artist
-        );
-        if file_manager.is_completed(item).unwrap() {
-            println!("  Item already processed");
-            continue;
-        }
-        println!("  Analysing download page {:?}", &item.download_url);
-        let contents = downloader
-            .get_page(&item.download_url)
-            .unwrap()
-            .text()
-            .unwrap();
-        let download_options = parse_download_page(&contents).unwrap();
-        let download_option = pick_format(&format_preferences, &download_options).unwrap();
-        println!(
-            "  Downloading data (~{} bytes) {:?}",
-            download_option.approximate_size, download_option.url,
-        );
-        let mut media_download = downloader.get_media(&download_option.url).unwrap();
-        handle_download_response(&file_manager, item, &mut media_download).unwrap();
-        println!("  Finished processing item");
+}
+
+impl From<reqwest::Error> for ListingError {
+    fn from(err: reqwest::Error) -> Self {
+        ListingError::BadRequest(err)
     }
 }
 
-fn main() {
-    match std::panic::catch_unwind(|| {
-        main_impl();
-    }) {
-        Ok(_) => {}
+impl From<ParsePageError> for ListingError {
+    fn from(err: ParsePageError) -> Self {
+        ListingError::BadPage(err)
+    }
+}
+
+/// Parse a page of the fan's listings, which nothing can be done without
+fn parse_listing<T>(
+    url: &str,
+    contents: &str,
+    parse: impl Fn(&str) -> Result<T, ParsePageError>,
+) -> Result<T, ParsePageError> {
+    parse(contents).map_err(|err| err.in_page(url, contents))
+}
+
+/// Save the data of the page that a parse error was found in to `directory` for bug reports,
+/// returning what to tell the user about it
+fn save_diagnostics(directory: Option<&std::path::Path>, err: &ParsePageError) -> Option<String> {
+    let (url, contents) = match err {
+        ParsePageError::InPage { url, contents, .. } => (url, contents),
+        _ => return None,
+    };
+    Some(match save_page_data(directory?, url, contents) {
+        Ok(path) => format!("Page data saved to {:?}", path),
+        Err(err) => format!("Could not save page data for diagnostics: {}", err),
+    })
+}
+
+/// Write the data of a page to a new file in `directory`
+fn save_page_data(
+    directory: &std::path::Path,
+    url: &str,
+    contents: &str,
+) -> Result<std::path::PathBuf, std::io::Error> {
+    std::fs::create_dir_all(directory)?;
+    let (data, extension) = page_data(contents);
+    let page = RE_NOT_FILENAME.replace_all(url.split('?').next().unwrap_or_default(), "_");
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let path = directory.join(format!("{}-{}.{}", now.as_millis(), page, extension));
+    std::fs::write(&path, data)?;
+    Ok(path)
+}
+
+/// An item for the workers, with what its download page offers
+/// if that was already read to expand a bundle
+type QueuedItem = (CollectionItem, Option<DigitalItem>);
+
+/// Send an item to `items`, or each of its releases if it is a bundle
+fn send_item(
+    downloader: &Downloader,
+    item: CollectionItem,
+    items: &std::sync::mpsc::Sender<QueuedItem>,
+) {
+    if item.itype != CollectionItemKind::Bundle || item.download_url.is_none() {
+        // the receiving workers only hang up early if they panicked
+        let _ = items.send((item, None));
+        return;
+    }
+    match expand_bundle(downloader, &item) {
+        Ok(releases) => {
+            for (release, digital_item) in releases {
+                let _ = items.send((release, Some(digital_item)));
+            }
+        }
+        // the bundle is then downloaded as a whole, which reports the error if it persists
         Err(_) => {
-            std::thread::sleep(std::time::Duration::new(10, 0));
+            let _ = items.send((item, None));
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::downloader::*;
-    use crate::file_manager::*;
-    use crate::handle_download_response;
-    use crate::types::*;
+/// The releases of a bundle, as listed on its download page
+fn expand_bundle(
+    downloader: &Downloader,
+    item: &CollectionItem,
+) -> Result<Vec<(CollectionItem, DigitalItem)>, ItemError> {
+    let download_url = match &item.download_url {
+        Some(download_url) => download_url,
+        None => return Ok(vec![]),
+    };
+    let contents = downloader.get_page(download_url)?;
+    let digital_items =
+        parse_download_page(&contents).map_err(|err| err.in_page(download_url, &contents))?;
+    Ok(split_digital_items(item, digital_items))
+}
 
-    struct MockMediaDownload {
-        filename: String,
+/// Fetch every item on the wishlist, following the same pagination as the collection
+fn process_wishlist_link(
+    downloader: &Downloader,
+    collection_link: &str,
+) -> Result<Vec<WishlistItem>, ListingError> {
+    let collection_page = downloader.get_page(collection_link)?;
+    let wishlist_page_data = parse_listing(collection_link, &collection_page, parse_wishlist_page)?;
+    let mut items = wishlist_page_data.wishlist_items;
+    fetch_following_pages(
+        downloader,
+        "api/fancollection/1/wishlist_items",
+        wishlist_page_data.fan_id,
+        wishlist_page_data.last_token,
+        parse_wishlist_json,
+        |page| items.extend(page),
+    )?;
+    Ok(items)
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
     }
-    impl MediaDownload for MockMediaDownload {
-        fn get_filename(&self) -> &str {
-            &self.filename
+}
+
+fn write_wishlist<W: Write>(
+    writer: &mut W,
+    items: &[WishlistItem],
+    format: &ExportFormat,
+) -> Result<(), std::io::Error> {
+    match format {
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(&mut *writer, items)?;
+            writeln!(writer)?;
         }
-        fn save<W: ?Sized + std::io::Write>(
-            &mut self,
-            writer: &mut W,
-        ) -> Result<(), reqwest::Error> {
-            let mut source =
-                std::fs::File::open(std::path::Path::new("testdata").join(&self.filename)).unwrap();
-            let xxx = std::io::copy(&mut source, writer).unwrap();
-            println!("{:?}", xxx);
-            Ok(())
+        ExportFormat::Csv => {
+            writeln!(writer, "artist,title,url,price,currency")?;
+            for item in items.iter() {
+                let fields = [
+                    item.artist.clone(),
+                    item.title.clone(),
+                    item.url.clone().unwrap_or_default(),
+                    item.price
+                        .map(|price| price.to_string())
+                        .unwrap_or_default(),
+                    item.currency.clone().unwrap_or_default(),
+                ];
+                let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+                writeln!(writer, "{}", fields.join(","))?;
+            }
         }
     }
-    struct MockBadMediaDownload {
-        filename: String,
+    Ok(())
+}
+
+fn list_wishlist(
+    settings: &Args,
+    export: Option<&ExportFormat>,
+    output: Option<&std::path::Path>,
+) -> std::process::ExitCode {
+    let (config, retry_policy, rate_limits) = match network_settings(settings) {
+        Ok(network_settings) => network_settings,
+        Err(err) => {
+            println!("Could not open recording {}", err);
+            return std::process::ExitCode::FAILURE;
+        }
+    };
+    let (collection_link, downloader) = match get_collection_link(
+        settings.username.clone(),
+        &config,
+        &retry_policy,
+        &rate_limits,
+        &diagnostics_directory(settings),
+    ) {
+        Some(login) => login,
+        None => return std::process::ExitCode::FAILURE,
+    };
+    let items = match process_wishlist_link(&downloader, &collection_link) {
+        Ok(items) => items,
+        Err(err) => {
+            eprintln!("Could not list the wishlist: {}", err);
+            if let ListingError::BadPage(err) = &err {
+                if let Some(message) = save_diagnostics(Some(&diagnostics_directory(settings)), err)
+                {
+                    eprintln!("{}", message);
+                }
+            }
+            return std::process::ExitCode::FAILURE;
+        }
+    };
+    let result = match (export, output) {
+        (None, _) => {
+            for item in items.iter() {
+                let price = match (item.price, &item.currency) {
+                    (Some(price), Some(currency)) => format!(" ({:.2} {})", price, currency),
+                    _ => "".to_string(),
+                };
+                println!("{} - {}{}", item.artist, item.title, price);
+                if let Some(url) = &item.url {
+                    println!("  {}", url);
+                }
+            }
+            Ok(())
+        }
+        (Some(format), Some(output)) => std::fs::File::create(output)
+            .and_then(|mut file| write_wishlist(&mut file, &items, format)),
+        (Some(format), None) => write_wishlist(&mut std::io::stdout().lock(), &items, format),
+    };
+    match result {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Could not export the wishlist: {}", err);
+            std::process::ExitCode::FAILURE
+        }
     }
-    impl MediaDownload for MockBadMediaDownload {
-        fn get_filename(&self) -> &str {
-            &self.filename
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ItemOutcome {
+    Downloaded,
+    Skipped,
+}
+
+/// Everything that can go wrong while processing a single collection item
+#[derive(Debug)]
+enum ItemError {
+    BadRequest(reqwest::Error),
+    BadPage(ParsePageError),
+    NoMatchingFormat(Vec<Encoding>),
+    BadMedia(GetMediaError),
+    BadDownload(HandleDownloadResponseError),
+    MissingDownloadUrl,
+}
+
+impl std::fmt::Display for ItemError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ItemError::BadRequest(e) => write!(f, "download page request failed: {}", e),
+            ItemError::BadPage(e) => write!(f, "could not read download page: {}", e),
+            ItemError::NoMatchingFormat(available) => {
+                let available: Vec<String> = available.iter().map(|e| e.to_string()).collect();
+                write!(
+                    f,
+                    "requested format is not available (available: {})",
+                    available.join(", ")
+                )
+            }
+            ItemError::BadMedia(e) => write!(f, "{}", e),
+            ItemError::BadDownload(e) => write!(f, "{}", e),
+            ItemError::MissingDownloadUrl => {
+                write!(
+                    f,
+                    "the collection data has no download page for this purchase"
+                )
+            }
         }
-        fn save<W: ?Sized + std::io::Write>(
-            &mut self,
-            _writer: &mut W,
-        ) -> Result<(), reqwest::Error> {
-            panic!("BAD");
+    }
+}
+
+impl ItemError {
+    /// The error in a page that Bandcamp served, if that is what went wrong
+    fn page_error(&self) -> Option<&ParsePageError> {
+        match self {
+            ItemError::BadPage(err)
+            | ItemError::BadMedia(GetMediaError::InvalidDownloadStatus(err)) => Some(err),
+            _ => None,
         }
     }
+}
 
-    #[test]
-    fn download_response_unzip() {
-        let mut dir = std::env::temp_dir();
-        dir.push(format!(
-            "test-{}",
-            std::time::SystemTime::now()
-                .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs_f64()
-        ));
-        std::fs::create_dir(&dir).unwrap();
-        let file_manager = FileManager {
-            root_directory: dir.clone(),
-        };
-        let item = CollectionItem {
+impl From<reqwest::Error> for ItemError {
+    fn from(err: reqwest::Error) -> Self {
+        ItemError::BadRequest(err)
+    }
+}
+
+impl From<ParsePageError> for ItemError {
+    fn from(err: ParsePageError) -> Self {
+        ItemError::BadPage(err)
+    }
+}
+
+impl From<GetMediaError> for ItemError {
+    fn from(err: GetMediaError) -> Self {
+        ItemError::BadMedia(err)
+    }
+}
+
+impl From<HandleDownloadResponseError> for ItemError {
+    fn from(err: HandleDownloadResponseError) -> Self {
+        ItemError::BadDownload(err)
+    }
+}
+
+impl From<std::io::Error> for ItemError {
+    fn from(err: std::io::Error) -> Self {
+        ItemError::BadDownload(HandleDownloadResponseError::BadIO(err))
+    }
+}
+
+/// What to download for each item
+struct SyncOptions {
+    format_preferences: Vec<Encoding>,
+    /// The size of cover art to save, if any
+    cover_art: Option<ArtSize>,
+    /// Check extracted albums against their track listing
+    check_tracks: bool,
+    /// Also download the items hidden from the public collection
+    include_hidden: bool,
+    /// Download the bonus files of albums
+    extras: bool,
+    /// Save the lyrics, credits and about text of albums
+    texts: bool,
+    jobs: usize,
+    /// Where to save the data of pages that could not be read
+    diagnostics_directory: Option<std::path::PathBuf>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            format_preferences: vec![Encoding::Flac],
+            cover_art: Some(ArtSize::Large),
+            check_tracks: true,
+            include_hidden: false,
+            extras: true,
+            texts: false,
+            jobs: 1,
+            diagnostics_directory: None,
+        }
+    }
+}
+
+/// Save an item's cover art next to its music, unless it is already there
+fn download_cover_art(
+    console: &ItemConsole,
+    downloader: &Downloader,
+    file_manager: &FileManager,
+    item: &CollectionItem,
+    size: &ArtSize,
+) -> Result<(), ItemError> {
+    let art_id = match item.art_id {
+        Some(art_id) => art_id,
+        None => return Ok(()),
+    };
+    let missing: Vec<std::path::PathBuf> = file_manager
+        .get_art_paths(item)
+        .into_iter()
+        .filter(|path| !path.exists())
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    console.println("  Downloading cover art");
+    let art = downloader.get_art(art_id, size)?;
+    for path in missing {
+        file_manager.save_file(&path, &art)?;
+    }
+    Ok(())
+}
+
+/// Save the texts of a finished album that are not there yet, reading its album page
+/// unless the download already did
+fn save_album_texts(
+    downloader: &Downloader,
+    file_manager: &FileManager,
+    item: &CollectionItem,
+    album_page: Option<&AlbumPageData>,
+) -> Result<(), ItemError> {
+    let item_url = match (&item.itype, &item.item_url) {
+        (CollectionItemKind::Album, Some(item_url)) => item_url,
+        _ => return Ok(()),
+    };
+    // another worker may still be downloading it
+    if !file_manager.is_completed(item)? {
+        return Ok(());
+    }
+    let fetched;
+    let album_page = match album_page {
+        Some(album_page) => album_page,
+        None => {
+            fetched = get_album_page(downloader, item_url)?;
+            &fetched
+        }
+    };
+    let files = file_manager.save_album_texts(item, album_page)?;
+    if !files.is_empty() {
+        file_manager.complete(item, &files)?;
+    }
+    Ok(())
+}
+
+fn process_item(
+    console: &ItemConsole,
+    downloader: &Downloader,
+    file_manager: &FileManager,
+    options: &SyncOptions,
+    item: &CollectionItem,
+    digital_item: Option<DigitalItem>,
+) -> Result<ItemOutcome, ItemError> {
+    console.println(format!(
+        "Processing item: {:?} \"{}\" by \"{}\"",
+        item.itype, item.title, item.artist
+    ));
+    let outcomes = if item.download_url.is_none() {
+        // only merch can come without anything to download
+        if item.itype != CollectionItemKind::Package {
+            return Err(ItemError::MissingDownloadUrl);
+        }
+        console.println("  Nothing to download for this purchase");
+        vec![]
+    } else if file_manager.is_completed(item)? {
+        console.println("  Item already processed");
+        vec![(item.clone(), ItemOutcome::Skipped, None)]
+    } else {
+        download_item(
+            console,
+            downloader,
+            file_manager,
+            options,
+            item,
+            digital_item,
+        )?
+    };
+    if let Some(size) = &options.cover_art {
+        for (item, _, _) in outcomes.iter() {
+            // missing artwork is not worth failing the item over
+            if let Err(err) = download_cover_art(console, downloader, file_manager, item, size) {
+                console.println(format!("  Could not download cover art: {}", err));
+            }
+        }
+    }
+    if options.texts {
+        for (item, _, album_page) in outcomes.iter() {
+            // like cover art, missing texts are not worth failing the album over
+            if let Err(err) = save_album_texts(downloader, file_manager, item, album_page.as_ref())
+            {
+                console.println(format!("  Could not save album texts: {}", err));
+            }
+        }
+    }
+    console.println("  Finished processing item");
+    if outcomes
+        .iter()
+        .any(|(_, outcome, _)| outcome == &ItemOutcome::Downloaded)
+    {
+        Ok(ItemOutcome::Downloaded)
+    } else {
+        Ok(ItemOutcome::Skipped)
+    }
+}
+
+/// The item to save each digital item of a download page as; a page with several,
+/// such as a bundle's, has each of them saved like a separate purchase
+fn split_digital_items(
+    item: &CollectionItem,
+    digital_items: Vec<DigitalItem>,
+) -> Vec<(CollectionItem, DigitalItem)> {
+    if digital_items.len() == 1 && !item.itype.contains_releases() {
+        let digital_item = digital_items.into_iter().next().unwrap();
+        return vec![(item.clone(), digital_item)];
+    }
+    digital_items
+        .into_iter()
+        .map(|digital_item| {
+            // the ids and price of the purchase are not those of its parts
+            let part = CollectionItem {
+                itype: digital_item.itype.clone(),
+                title: digital_item.title.clone(),
+                artist: digital_item
+                    .artist
+                    .clone()
+                    .unwrap_or_else(|| item.artist.clone()),
+                download_url: item.download_url.clone(),
+                item_url: digital_item.item_url.clone(),
+                art_id: digital_item.art_id,
+                purchased: item.purchased.clone(),
+                bundle: Some(item.title.clone()),
+                ..CollectionItem::default()
+            };
+            (part, digital_item)
+        })
+        .collect()
+}
+
+fn get_album_page(downloader: &Downloader, item_url: &str) -> Result<AlbumPageData, ItemError> {
+    let contents = downloader.get_page(item_url)?;
+    Ok(parse_album_page(&contents).map_err(|err| err.in_page(item_url, &contents))?)
+}
+
+/// What became of one digital item, with the album page read while downloading it
+type PartOutcome = (CollectionItem, ItemOutcome, Option<AlbumPageData>);
+
+/// Download every digital item on the item's download page,
+/// returning the item each was saved as
+fn download_item(
+    console: &ItemConsole,
+    downloader: &Downloader,
+    file_manager: &FileManager,
+    options: &SyncOptions,
+    item: &CollectionItem,
+    digital_item: Option<DigitalItem>,
+) -> Result<Vec<PartOutcome>, ItemError> {
+    if let Some(digital_item) = digital_item {
+        // a release of a bundle that was expanded from the bundle's download page
+        return Ok(vec![download_part(
+            console,
+            downloader,
+            file_manager,
+            options,
+            item,
+            &digital_item,
+            true,
+        )?]);
+    }
+    let download_url = item
+        .download_url
+        .as_ref()
+        .ok_or(ItemError::MissingDownloadUrl)?;
+    console.println(format!("  Analysing download page {:?}", download_url));
+    let contents = downloader.get_page(download_url)?;
+    let digital_items = match parse_download_page(&contents) {
+        Err(ParsePageError::NoDigitalItems) if item.itype == CollectionItemKind::Package => {
+            console.println("  This merch has no digital copy to download");
+            return Ok(vec![]);
+        }
+        digital_items => digital_items.map_err(|err| err.in_page(download_url, &contents))?,
+    };
+    let parts = split_digital_items(item, digital_items);
+    let several = parts.len() > 1 || item.itype.contains_releases();
+    let mut outcomes = vec![];
+    for (part, digital_item) in parts {
+        outcomes.push(download_part(
+            console,
+            downloader,
+            file_manager,
+            options,
+            &part,
+            &digital_item,
+            several,
+        )?);
+    }
+    Ok(outcomes)
+}
+
+/// Download one digital item of a download page, unless another worker already is;
+/// `separate` parts are announced, since they are not the purchase itself
+fn download_part(
+    console: &ItemConsole,
+    downloader: &Downloader,
+    file_manager: &FileManager,
+    options: &SyncOptions,
+    part: &CollectionItem,
+    digital_item: &DigitalItem,
+    separate: bool,
+) -> Result<PartOutcome, ItemError> {
+    // the same album can be bought on its own and in a bundle
+    let _claim = match file_manager.claim(part) {
+        Some(claim) => claim,
+        None => {
+            console.println(format!(
+                "  Skipping \"{}\" (another job is downloading it)",
+                part.title
+            ));
+            return Ok((part.clone(), ItemOutcome::Skipped, None));
+        }
+    };
+    // checked again, as another worker may have finished it since
+    let (outcome, album_page) = if file_manager.is_completed(part)? {
+        (ItemOutcome::Skipped, None)
+    } else {
+        if separate {
+            console.println(format!(
+                "  Downloading {:?} \"{}\" by \"{}\"",
+                part.itype, part.title, part.artist
+            ));
+        }
+        download_digital_item(
+            console,
+            downloader,
+            file_manager,
+            options,
+            part,
+            digital_item,
+        )?
+    };
+    Ok((part.clone(), outcome, album_page))
+}
+
+fn download_digital_item(
+    console: &ItemConsole,
+    downloader: &Downloader,
+    file_manager: &FileManager,
+    options: &SyncOptions,
+    item: &CollectionItem,
+    digital_item: &DigitalItem,
+) -> Result<(ItemOutcome, Option<AlbumPageData>), ItemError> {
+    if downloader.is_replaying() {
+        // recordings keep only the headers of media, which would be saved as empty files
+        console.println("  Skipping download (media is not part of the recording)");
+        return Ok((ItemOutcome::Skipped, None));
+    }
+    let download_options = &digital_item.downloads;
+    let download_option =
+        pick_format(&options.format_preferences, download_options).ok_or_else(|| {
+            ItemError::NoMatchingFormat(
+                download_options
+                    .iter()
+                    .map(|option| option.encoding.clone())
+                    .collect(),
+            )
+        })?;
+    // extras go first, so that the album stays incomplete until they are all there
+    let extra_files = match item.itype {
+        CollectionItemKind::Album if options.extras => download_extras(
+            console,
+            downloader,
+            file_manager,
+            item,
+            &digital_item.extras,
+        )?,
+        _ => vec![],
+    };
+    console.println(format!(
+        "  Downloading data (~{} bytes) {:?}",
+        download_option.approximate_size, download_option.url,
+    ));
+    let album_page = match (&item.itype, &item.item_url) {
+        (CollectionItemKind::Album, Some(item_url)) if options.check_tracks || options.texts => {
+            // without its album page, the album can still be downloaded unchecked
+            match get_album_page(downloader, item_url) {
+                Ok(album_page) => Some(album_page),
+                Err(err) => {
+                    console.println(format!("  Could not read the album page: {}", err));
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let mut media_download = downloader.get_media(&download_option.url)?;
+    let outcome = handle_download_response(
+        console,
+        file_manager,
+        item,
+        &mut media_download,
+        console.progress().as_mut(),
+        album_page
+            .as_ref()
+            .filter(|_| options.check_tracks)
+            .map(|album_page| album_page.tracks.as_slice()),
+    )?;
+    if !extra_files.is_empty() {
+        file_manager.complete(item, &extra_files)?;
+    }
+    Ok((outcome, album_page))
+}
+
+/// Save an album's bonus files in its extras folder, returning where they are
+fn download_extras(
+    console: &ItemConsole,
+    downloader: &Downloader,
+    file_manager: &FileManager,
+    item: &CollectionItem,
+    extras: &[ExtraItem],
+) -> Result<Vec<std::path::PathBuf>, ItemError> {
+    let mut files = vec![];
+    for extra in extras.iter() {
+        // an earlier run that failed later on may have saved it already
+        if let Some(realfile) = file_manager.find_extra(item, &extra.title) {
+            files.push(realfile);
+            continue;
+        }
+        console.println(format!("  Downloading extra {:?}", extra.title));
+        let mut media_download = downloader.get_media(&extra.url)?;
+        let filename: std::path::PathBuf = media_download.get_filename().to_string().into();
+        let (tempfile, realfile) =
+            file_manager.get_extra_filepath(item, &extra.title, &filename)?;
+        download_to_tempfile(
+            console,
+            &mut media_download,
+            console.progress().as_mut(),
+            &tempfile,
+        )?;
+        rename_tempfile(&tempfile, &realfile)?;
+        files.push(realfile);
+    }
+    Ok(files)
+}
+
+const EXIT_PARTIAL_FAILURE: u8 = 2;
+const EXIT_TOTAL_FAILURE: u8 = 3;
+
+type ItemResult = (usize, CollectionItem, Result<ItemOutcome, ItemError>);
+
+fn report_results(results: &[ItemResult]) -> std::process::ExitCode {
+    let mut succeeded = 0;
+    let mut skipped = 0;
+    let mut failed = vec![];
+    for (index, item, result) in results.iter() {
+        match result {
+            Ok(ItemOutcome::Downloaded) => succeeded += 1,
+            Ok(ItemOutcome::Skipped) => skipped += 1,
+            Err(err) => failed.push((*index, item, err)),
+        }
+    }
+    println!();
+    println!("{:<10} {:>6}", "Result", "Items");
+    println!("{:<10} {:>6}", "succeeded", succeeded);
+    println!("{:<10} {:>6}", "skipped", skipped);
+    println!("{:<10} {:>6}", "failed", failed.len());
+    if failed.is_empty() {
+        return std::process::ExitCode::SUCCESS;
+    }
+    println!();
+    println!("Failed items:");
+    for (index, item, err) in failed.iter() {
+        println!(
+            "  [{}] {:?} \"{}\" by \"{}\": {}",
+            index + 1,
+            item.itype,
+            item.title,
+            item.artist,
+            err
+        );
+    }
+    if succeeded + skipped == 0 {
+        std::process::ExitCode::from(EXIT_TOTAL_FAILURE)
+    } else {
+        std::process::ExitCode::from(EXIT_PARTIAL_FAILURE)
+    }
+}
+
+fn get_file_manager(path: Option<std::ffi::OsString>) -> FileManager {
+    let root_directory = match path {
+        Some(p) => p.into(),
+        None => {
+            println!("No download folder was specified when starting the program.");
+            println!("Please select a folder in which you want to download your music.");
+            let folder = rfd::FileDialog::new()
+                .set_title("Bandcamp Downloads Folder")
+                .pick_folder();
+            folder.expect("No folder selected")
+        }
+    };
+    let file_manager = FileManager::new(root_directory);
+    if !file_manager.root_directory.exists() {
+        std::fs::create_dir(&file_manager.root_directory).unwrap();
+    }
+    file_manager
+}
+
+/// The network settings shared by every command that talks to Bandcamp,
+/// or why the recording they use could not be opened
+fn network_settings(
+    settings: &Args,
+) -> Result<(DownloaderConfig, RetryPolicy, RateLimits), std::io::Error> {
+    let retry_policy = RetryPolicy {
+        max_attempts: settings.retries.max(1),
+        ..RetryPolicy::default()
+    };
+    let rate_limits = RateLimits {
+        page_requests: settings.request_rate,
+        media_requests: settings.media_rate,
+    };
+    let config = DownloaderConfig {
+        proxy: settings.proxy.clone(),
+        root_certificates: settings.root_certificates.clone(),
+        local_address: settings.local_address,
+        timeout: std::time::Duration::new(settings.timeout, 0),
+        media_timeout: std::time::Duration::new(settings.media_timeout, 0),
+        prepare_timeout: std::time::Duration::new(settings.prepare_timeout, 0),
+        user_agent: settings.user_agent.clone(),
+        base_url: settings.base_url.clone(),
+        recorder: match (&settings.record, &settings.replay) {
+            (Some(directory), _) => Some(std::sync::Arc::new(
+                recorder::Recorder::record(directory.clone())
+                    .map_err(|err| in_directory(directory, err))?,
+            )),
+            (_, Some(directory)) => Some(std::sync::Arc::new(
+                recorder::Recorder::replay(directory.clone())
+                    .map_err(|err| in_directory(directory, err))?,
+            )),
+            (None, None) => None,
+        },
+        ..DownloaderConfig::default()
+    };
+    Ok((config, retry_policy, rate_limits))
+}
+
+/// Name the directory an I/O error happened in
+fn in_directory(directory: &std::path::Path, err: std::io::Error) -> std::io::Error {
+    std::io::Error::new(err.kind(), format!("{:?}: {}", directory, err))
+}
+
+fn diagnostics_directory(settings: &Args) -> std::path::PathBuf {
+    settings
+        .diagnostics_dir
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join("bandcamper-diagnostics"))
+}
+
+fn sync_collection(settings: &Args, file_manager: &FileManager) -> std::process::ExitCode {
+    let options = SyncOptions {
+        format_preferences: vec![settings.format.clone()],
+        cover_art: if settings.no_cover_art {
+            None
+        } else {
+            Some(settings.cover_size.clone())
+        },
+        check_tracks: !settings.no_track_check,
+        include_hidden: settings.include_hidden,
+        extras: !settings.no_extras,
+        texts: settings.save_texts,
+        jobs: settings.jobs,
+        diagnostics_directory: Some(diagnostics_directory(settings)),
+    };
+    println!("Scanning for Bandcamp collection data...");
+    let (config, retry_policy, rate_limits) = match network_settings(settings) {
+        Ok(network_settings) => network_settings,
+        Err(err) => {
+            println!("Could not open recording {}", err);
+            return std::process::ExitCode::FAILURE;
+        }
+    };
+    let (collection_link, downloader) = match get_collection_link(
+        settings.username.clone(),
+        &config,
+        &retry_policy,
+        &rate_limits,
+        &diagnostics_directory(settings),
+    ) {
+        Some(login) => login,
+        None => return std::process::ExitCode::FAILURE,
+    };
+    let downloader = downloader.with_bandwidth_limit(&BandwidthLimit {
+        bytes_per_second: settings.limit_rate.unwrap_or(0f64),
+        full_speed: settings.full_speed_hours.clone(),
+    });
+    let (results, listing) =
+        download_collection(&downloader, &collection_link, file_manager, &options);
+    let exit_code = report_results(&results);
+    match listing {
+        Ok(()) => exit_code,
+        // the error was reported as soon as the listing stopped
+        Err(_) => std::process::ExitCode::FAILURE,
+    }
+}
+
+/// Process every item in the collection on `options.jobs` workers,
+/// returning the results in collection order along with whether every item was listed
+fn download_collection(
+    downloader: &Downloader,
+    collection_link: &str,
+    file_manager: &FileManager,
+    options: &SyncOptions,
+) -> (Vec<ItemResult>, Result<(), ListingError>) {
+    // the collection size is unknown until pagination finishes
+    let console = Console::new(None);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let receiver = std::sync::Mutex::new(receiver);
+    let next_index = std::sync::atomic::AtomicUsize::new(0);
+    let results: std::sync::Mutex<Vec<ItemResult>> = std::sync::Mutex::new(vec![]);
+    let listing = std::thread::scope(|scope| {
+        let console = &console;
+        let listing = scope.spawn(move || {
+            let listing = process_collection_link(
+                downloader,
+                collection_link,
+                options.include_hidden,
+                sender,
+            );
+            if let Err(err) = &listing {
+                console.println(format!("Could not list the collection: {}", err));
+                if let ListingError::BadPage(err) = err {
+                    if let Some(message) =
+                        save_diagnostics(options.diagnostics_directory.as_deref(), err)
+                    {
+                        console.println(message);
+                    }
+                }
+            }
+            listing
+        });
+        for _ in 0..options.jobs.max(1) {
+            scope.spawn(|| loop {
+                // the index is taken under the lock so that items keep their collection order
+                let (index, (item, digital_item)) = {
+                    let receiver = receiver.lock().unwrap();
+                    match receiver.recv() {
+                        Ok(item) => (
+                            next_index.fetch_add(1, std::sync::atomic::Ordering::SeqCst),
+                            item,
+                        ),
+                        Err(_) => break,
+                    }
+                };
+                let item_console = console.item(index);
+                let result = process_item(
+                    &item_console,
+                    downloader,
+                    file_manager,
+                    options,
+                    &item,
+                    digital_item,
+                );
+                if let Err(err) = &result {
+                    item_console.println(format!("  Failed to process item: {}", err));
+                    if let Some(message) = err.page_error().and_then(|err| {
+                        save_diagnostics(options.diagnostics_directory.as_deref(), err)
+                    }) {
+                        item_console.println(format!("  {}", message));
+                    }
+                }
+                results.lock().unwrap().push((index, item, result));
+            });
+        }
+        listing.join().unwrap()
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _, _)| *index);
+    (results, listing)
+}
+
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// The outcome of checking every manifest in the library
+#[derive(Debug, Default)]
+struct LibraryCheck {
+    checked: usize,
+    failed: Vec<(std::path::PathBuf, Vec<VerifyFailure>)>,
+    /// Directories whose manifest or files could not be read
+    unreadable: Vec<std::path::PathBuf>,
+}
+
+fn check_library(file_manager: &FileManager) -> Result<LibraryCheck, std::io::Error> {
+    let mut check = LibraryCheck::default();
+    for directory in file_manager.get_manifest_directories()? {
+        let relative = directory
+            .strip_prefix(&file_manager.root_directory)
+            .unwrap_or(&directory);
+        let verified = Manifest::load(&directory)
+            .and_then(|manifest| Ok((manifest.verify(&directory)?, manifest.files.len())));
+        let (failures, files) = match verified {
+            Ok(verified) => verified,
+            Err(err) => {
+                println!("  {:?} could not be checked: {}", relative, err);
+                check.unreadable.push(directory);
+                continue;
+            }
+        };
+        for failure in failures.iter() {
+            let problem = match failure {
+                VerifyFailure::Missing(_) => "is missing",
+                VerifyFailure::Changed(_) => "does not match its checksum",
+            };
+            println!("  {:?} {}", relative.join(failure.filename()), problem);
+        }
+        check.checked += files;
+        if !failures.is_empty() {
+            check.failed.push((directory, failures));
+        }
+    }
+    Ok(check)
+}
+
+fn verify_library(
+    settings: &Args,
+    file_manager: &FileManager,
+    yes: bool,
+) -> std::process::ExitCode {
+    println!("Verifying downloaded music...");
+    let check = match check_library(file_manager) {
+        Ok(check) => check,
+        Err(err) => {
+            eprintln!("Could not search the library: {}", err);
+            return std::process::ExitCode::from(EXIT_TOTAL_FAILURE);
+        }
+    };
+    let failed_files: usize = check.failed.iter().map(|(_, f)| f.len()).sum();
+    println!("Checked {} files, {} failed", check.checked, failed_files);
+    if !check.unreadable.is_empty() {
+        println!(
+            "{} directories could not be checked",
+            check.unreadable.len()
+        );
+    }
+    let code = if check.unreadable.is_empty() {
+        std::process::ExitCode::SUCCESS
+    } else {
+        std::process::ExitCode::from(EXIT_PARTIAL_FAILURE)
+    };
+    if check.failed.is_empty() {
+        return code;
+    }
+    if !yes && !confirm("Download the items with failed files again?") {
+        return std::process::ExitCode::from(EXIT_PARTIAL_FAILURE);
+    }
+    for (directory, failures) in check.failed.iter() {
+        if let Err(err) = file_manager.invalidate(directory, failures) {
+            eprintln!("Could not mark {:?} for download: {}", directory, err);
+        }
+    }
+    // a successful sync still leaves the unreadable directories unchecked
+    let synced = sync_collection(settings, file_manager);
+    if synced == std::process::ExitCode::SUCCESS {
+        code
+    } else {
+        synced
+    }
+}
+
+fn main_impl() -> std::process::ExitCode {
+    let settings = Args::parse();
+    match &settings.command {
+        Some(Command::Verify { yes, path }) => {
+            let file_manager = get_file_manager(path.clone());
+            verify_library(&settings, &file_manager, *yes)
+        }
+        Some(Command::Wishlist { export, output }) => {
+            list_wishlist(&settings, export.as_ref(), output.as_deref())
+        }
+        None => {
+            let file_manager = get_file_manager(settings.path.clone());
+            sync_collection(&settings, &file_manager)
+        }
+    }
+}
+
+fn main() -> std::process::ExitCode {
+    match std::panic::catch_unwind(main_impl) {
+        Ok(code) => code,
+        Err(_) => {
+            std::thread::sleep(std::time::Duration::new(10, 0));
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::console::*;
+    use crate::downloader::*;
+    use crate::file_manager::*;
+    use crate::manifest::*;
+    use crate::mock_server::*;
+    use crate::parser::{
+        parse_collection_page, parse_home_page, CollectionPageData, DigitalItem, TrackInfo,
+    };
+    use crate::progress::*;
+    use crate::recorder::Recorder;
+    use crate::types::*;
+    use crate::{
+        check_library, download_collection, find_missing_tracks, handle_download_response,
+        parse_byte_rate, parse_listing, process_collection_items, process_item,
+        process_wishlist_link, save_diagnostics, validator_path, write_wishlist,
+        HandleDownloadResponseError, ItemError, ItemOutcome, ItemResult, ListingError, SyncOptions,
+    };
+    use std::io::Seek;
+
+    /// A new empty directory for a test, distinct from those of tests running in parallel
+    fn test_directory() -> std::path::PathBuf {
+        static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let mut dir = std::env::temp_dir();
+        dir.push(format!(
+            "test-{}-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+            COUNT.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Vec<Progress>,
+        finished: Option<Progress>,
+    }
+    impl ProgressObserver for RecordingObserver {
+        fn on_progress(&mut self, progress: &Progress) {
+            self.events.push(progress.clone());
+        }
+        fn on_finish(&mut self, progress: &Progress) {
+            self.finished = Some(progress.clone());
+        }
+    }
+
+    const MOCK_VALIDATOR: &str = "\"mock\"";
+
+    struct MockMediaDownload {
+        filename: String,
+        offset: u64,
+    }
+    impl MediaDownload for MockMediaDownload {
+        fn get_filename(&self) -> &str {
+            &self.filename
+        }
+        fn get_content_length(&self) -> u64 {
+            std::fs::metadata(std::path::Path::new("testdata").join(&self.filename))
+                .unwrap()
+                .len()
+        }
+        fn get_validator(&self) -> Option<&str> {
+            Some(MOCK_VALIDATOR)
+        }
+        fn resume_from(
+            &mut self,
+            offset: u64,
+            validator: Option<&str>,
+        ) -> Result<u64, GetMediaError> {
+            // like a server answering If-Range, send everything if the file changed
+            self.offset = match validator {
+                Some(validator) if validator != MOCK_VALIDATOR => 0,
+                _ => offset,
+            };
+            Ok(self.offset)
+        }
+        fn reader(&mut self) -> Box<dyn std::io::Read + '_> {
+            let mut source =
+                std::fs::File::open(std::path::Path::new("testdata").join(&self.filename)).unwrap();
+            source.seek(std::io::SeekFrom::Start(self.offset)).unwrap();
+            Box::new(source)
+        }
+        fn save<W: ?Sized + std::io::Write>(
+            &mut self,
+            writer: &mut W,
+            observer: &mut dyn ProgressObserver,
+        ) -> Result<(), std::io::Error> {
+            let mut source =
+                std::fs::File::open(std::path::Path::new("testdata").join(&self.filename)).unwrap();
+            source.seek(std::io::SeekFrom::Start(self.offset)).unwrap();
+            let total = self.get_content_length();
+            let mut writer = ProgressWriter::new(writer, observer, self.offset, total);
+            let xxx = std::io::copy(&mut source, &mut writer).unwrap();
+            writer.finish();
+            println!("{:?}", xxx);
+            Ok(())
+        }
+    }
+    struct MockBadMediaDownload {
+        filename: String,
+    }
+    impl MediaDownload for MockBadMediaDownload {
+        fn get_filename(&self) -> &str {
+            &self.filename
+        }
+        fn get_content_length(&self) -> u64 {
+            panic!("BAD");
+        }
+        fn get_validator(&self) -> Option<&str> {
+            panic!("BAD");
+        }
+        fn resume_from(
+            &mut self,
+            _offset: u64,
+            _validator: Option<&str>,
+        ) -> Result<u64, GetMediaError> {
+            panic!("BAD");
+        }
+        fn reader(&mut self) -> Box<dyn std::io::Read + '_> {
+            panic!("BAD");
+        }
+        fn save<W: ?Sized + std::io::Write>(
+            &mut self,
+            _writer: &mut W,
+            _observer: &mut dyn ProgressObserver,
+        ) -> Result<(), std::io::Error> {
+            panic!("BAD");
+        }
+    }
+
+    #[test]
+    fn download_response_unzip() {
+        let dir = test_directory();
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(Some(1));
+        let item = CollectionItem {
             itype: CollectionItemKind::Album,
             title: "Abc 123".to_string(),
             artist: "My CR".to_string(),
-            download_url: "".to_string(),
+            download_url: None,
+            ..CollectionItem::default()
         };
         let mut media_download = MockMediaDownload {
             filename: "archive.zip".to_string(),
+            offset: 0,
         };
-        handle_download_response(&file_manager, &item, &mut media_download).unwrap();
+        let outcome = handle_download_response(
+            &console.item(0),
+            &file_manager,
+            &item,
+            &mut media_download,
+            &mut NoProgress,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome, ItemOutcome::Downloaded);
         let mut missing: std::collections::HashSet<std::ffi::OsString> =
             std::collections::HashSet::new();
         missing.insert("file1.flac".into());
         missing.insert("file2.flac".into());
+        missing.insert(MANIFEST_FILENAME.into());
         for entry in dir.join("My CR").join("Abc 123").read_dir().unwrap() {
             let name = entry.unwrap().file_name();
             assert!(missing.remove(&name));
@@ -377,44 +2005,1049 @@ mod tests {
         let mut bad_media_download = MockBadMediaDownload {
             filename: "track.flac".to_string(),
         };
-        handle_download_response(&file_manager, &item, &mut bad_media_download).unwrap();
+        let outcome = handle_download_response(
+            &console.item(0),
+            &file_manager,
+            &item,
+            &mut bad_media_download,
+            &mut NoProgress,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome, ItemOutcome::Skipped);
+    }
+
+    fn track(number: u32, title: &str) -> TrackInfo {
+        TrackInfo {
+            number: Some(number),
+            title: title.to_string(),
+            duration: None,
+            is_downloadable: Some(true),
+            lyrics: None,
+        }
     }
 
     #[test]
-    fn download_response_track() {
-        let mut dir = std::env::temp_dir();
-        dir.push(format!(
-            "test-{}",
-            std::time::SystemTime::now()
-                .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs_f64()
-        ));
-        std::fs::create_dir(&dir).unwrap();
-        let file_manager = FileManager {
-            root_directory: dir.clone(),
+    fn test_find_missing_tracks() {
+        let files: Vec<std::path::PathBuf> = vec![
+            "Artist - Album - 01 First Song.flac".into(),
+            "Artist - Album - 02 Second.flac".into(),
+            "cover.jpg".into(),
+            "Bonus Thing.flac".into(),
+        ];
+        let mut hidden = track(5, "Unreleased");
+        hidden.is_downloadable = Some(false);
+        let tracks = vec![
+            track(1, "First Song"),
+            track(2, "Second"),
+            track(3, "Third"),
+            track(4, "Bonus: Thing"),
+            hidden,
+        ];
+        let missing = find_missing_tracks(&tracks, &files);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].title, "Third");
+    }
+
+    #[test]
+    fn download_response_missing_tracks() {
+        let dir = test_directory();
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(Some(1));
+        let item = CollectionItem {
+            itype: CollectionItemKind::Album,
+            title: "Abc 123".to_string(),
+            artist: "My CR".to_string(),
+            ..CollectionItem::default()
         };
+        let mut media_download = MockMediaDownload {
+            filename: "archive.zip".to_string(),
+            offset: 0,
+        };
+        let tracks = vec![track(1, "file1"), track(2, "file2"), track(3, "file3")];
+        let result = handle_download_response(
+            &console.item(0),
+            &file_manager,
+            &item,
+            &mut media_download,
+            &mut NoProgress,
+            Some(&tracks),
+        );
+        match result {
+            Err(HandleDownloadResponseError::MissingTracks(missing)) => {
+                assert_eq!(missing, vec!["03 file3".to_string()]);
+            }
+            other => panic!(
+                "expected missing tracks, got {:?}",
+                other.map_err(|e| e.to_string())
+            ),
+        }
+        let album = dir.join("My CR").join("Abc 123");
+        assert!(album.join("file1.flac").exists());
+        assert!(album.join(".incomplete").exists());
+        assert!(!file_manager.is_completed(&item).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn download_response_track() {
+        let dir = test_directory();
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(Some(1));
         let mut media_download = MockMediaDownload {
             filename: "track.flac".to_string(),
+            offset: 0,
         };
         let item = CollectionItem {
             itype: CollectionItemKind::Track,
             title: "Hewwo".to_string(),
             artist: "Boopers".to_string(),
-            download_url: "".to_string(),
+            download_url: None,
+            ..CollectionItem::default()
         };
-        handle_download_response(&file_manager, &item, &mut media_download).unwrap();
+        let mut observer = RecordingObserver::default();
+        handle_download_response(
+            &console.item(0),
+            &file_manager,
+            &item,
+            &mut media_download,
+            &mut observer,
+            None,
+        )
+        .unwrap();
+        assert!(!observer.events.is_empty());
+        assert!(observer
+            .events
+            .windows(2)
+            .all(|w| w[0].transferred <= w[1].transferred));
+        let finished = observer.finished.unwrap();
+        assert_eq!(finished.transferred, 4);
+        assert_eq!(finished.total, 4);
+        assert_eq!(finished.eta, Some(std::time::Duration::ZERO));
         let mut missing: std::collections::HashSet<std::ffi::OsString> =
             std::collections::HashSet::new();
         missing.insert("track.flac".into());
+        missing.insert(MANIFEST_FILENAME.into());
         for entry in dir.join("Boopers").read_dir().unwrap() {
             let name = entry.unwrap().file_name();
             assert!(missing.remove(&name));
         }
         assert!(missing.is_empty());
+        let manifest = Manifest::load(&dir.join("Boopers")).unwrap();
+        assert_eq!(
+            manifest.files["track.flac"].sha256,
+            "3fb5311ff7066e902a92fe8b499a35ab7f1d45c4752d2e02eca897d2526e8681"
+        );
         let mut bad_media_download = MockBadMediaDownload {
             filename: "track.flac".to_string(),
         };
-        handle_download_response(&file_manager, &item, &mut bad_media_download).unwrap();
+        let outcome = handle_download_response(
+            &console.item(0),
+            &file_manager,
+            &item,
+            &mut bad_media_download,
+            &mut NoProgress,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome, ItemOutcome::Skipped);
+    }
+
+    #[test]
+    fn download_response_resume() {
+        let dir = test_directory();
+        std::fs::create_dir_all(dir.join("Boopers")).unwrap();
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(Some(1));
+        let item = CollectionItem {
+            itype: CollectionItemKind::Track,
+            title: "Hewwo".to_string(),
+            artist: "Boopers".to_string(),
+            download_url: None,
+            ..CollectionItem::default()
+        };
+        let original = std::fs::read(std::path::Path::new("testdata").join("track.flac")).unwrap();
+        std::fs::write(
+            dir.join("Boopers").join(".temporary.track.flac"),
+            &original[..2],
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("Boopers").join(".temporary.track.flac.validator"),
+            MOCK_VALIDATOR,
+        )
+        .unwrap();
+        let mut media_download = MockMediaDownload {
+            filename: "track.flac".to_string(),
+            offset: 0,
+        };
+        let mut observer = RecordingObserver::default();
+        handle_download_response(
+            &console.item(0),
+            &file_manager,
+            &item,
+            &mut media_download,
+            &mut observer,
+            None,
+        )
+        .unwrap();
+        assert_eq!(media_download.offset, 2);
+        assert!(observer.events.iter().all(|p| p.transferred > 2));
+        assert_eq!(observer.finished.unwrap().transferred, 4);
+        let downloaded = std::fs::read(dir.join("Boopers").join("track.flac")).unwrap();
+        assert_eq!(downloaded, original);
+        assert!(!dir.join("Boopers").join(".temporary.track.flac").exists());
+        assert!(!dir
+            .join("Boopers")
+            .join(".temporary.track.flac.validator")
+            .exists());
+    }
+
+    #[test]
+    fn download_response_restarts_changed_file() {
+        let dir = test_directory();
+        std::fs::create_dir_all(dir.join("Boopers")).unwrap();
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(Some(1));
+        let item = CollectionItem {
+            itype: CollectionItemKind::Track,
+            title: "Hewwo".to_string(),
+            artist: "Boopers".to_string(),
+            ..CollectionItem::default()
+        };
+        let original = std::fs::read(std::path::Path::new("testdata").join("track.flac")).unwrap();
+        let tempfile = dir.join("Boopers").join(".temporary.track.flac");
+        // the partial data is of an older version of the file
+        std::fs::write(&tempfile, b"xx").unwrap();
+        std::fs::write(validator_path(&tempfile), "\"old\"").unwrap();
+        let mut media_download = MockMediaDownload {
+            filename: "track.flac".to_string(),
+            offset: 0,
+        };
+        handle_download_response(
+            &console.item(0),
+            &file_manager,
+            &item,
+            &mut media_download,
+            &mut NoProgress,
+            None,
+        )
+        .unwrap();
+        assert_eq!(media_download.offset, 0);
+        let downloaded = std::fs::read(dir.join("Boopers").join("track.flac")).unwrap();
+        assert_eq!(downloaded, original);
+    }
+
+    #[test]
+    fn download_response_removes_bad_archive() {
+        let dir = test_directory();
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(Some(1));
+        let item = CollectionItem {
+            itype: CollectionItemKind::Album,
+            title: "Abc 123".to_string(),
+            artist: "My CR".to_string(),
+            ..CollectionItem::default()
+        };
+        let mut media_download = MockMediaDownload {
+            filename: "archive.zip".to_string(),
+            offset: 0,
+        };
+        // an earlier run left a complete download behind that turns out not to be an archive
+        let album = dir.join("My CR").join("Abc 123");
+        std::fs::create_dir_all(&album).unwrap();
+        std::fs::File::create(album.join(".incomplete")).unwrap();
+        let ziptemp = album.join(".temporary.archive.zip");
+        let length = media_download.get_content_length() as usize;
+        std::fs::write(&ziptemp, vec![0; length]).unwrap();
+        std::fs::write(validator_path(&ziptemp), MOCK_VALIDATOR).unwrap();
+        let result = handle_download_response(
+            &console.item(0),
+            &file_manager,
+            &item,
+            &mut media_download,
+            &mut NoProgress,
+            None,
+        );
+        assert!(
+            matches!(result, Err(HandleDownloadResponseError::BadZip(_))),
+            "{:?}",
+            result
+        );
+        assert!(!ziptemp.exists());
+        assert!(!validator_path(&ziptemp).exists());
+    }
+
+    #[test]
+    fn download_response_unzip_fallback() {
+        let dir = test_directory();
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(Some(1));
+        let item = CollectionItem {
+            itype: CollectionItemKind::Album,
+            title: "Abc 123".to_string(),
+            artist: "My CR".to_string(),
+            download_url: None,
+            ..CollectionItem::default()
+        };
+        // members with data descriptors can't be streamed
+        let mut media_download = MockMediaDownload {
+            filename: "archive-descriptor.zip".to_string(),
+            offset: 0,
+        };
+        handle_download_response(
+            &console.item(0),
+            &file_manager,
+            &item,
+            &mut media_download,
+            &mut NoProgress,
+            None,
+        )
+        .unwrap();
+        let album = dir.join("My CR").join("Abc 123");
+        assert_eq!(std::fs::read(album.join("file1.flac")).unwrap(), b"one\n");
+        assert_eq!(std::fs::read(album.join("file2.flac")).unwrap(), b"two\n");
+        let mut names: Vec<std::ffi::OsString> = album
+            .read_dir()
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec![MANIFEST_FILENAME, "file1.flac", "file2.flac"]);
+    }
+
+    #[test]
+    fn test_parse_byte_rate() {
+        assert_eq!(parse_byte_rate("1000"), Ok(1000f64));
+        assert_eq!(parse_byte_rate("500K"), Ok(512000f64));
+        assert_eq!(parse_byte_rate("5M"), Ok(5242880f64));
+        assert_eq!(parse_byte_rate("1.5m"), Ok(1572864f64));
+        assert_eq!(parse_byte_rate("1G"), Ok(1073741824f64));
+        assert!(parse_byte_rate("5X").is_err());
+        assert!(parse_byte_rate("fast").is_err());
+        assert!(parse_byte_rate("-1K").is_err());
+    }
+
+    fn mock_downloader(server: &MockServer) -> Downloader {
+        let config = DownloaderConfig {
+            base_url: server.base_url.clone(),
+            art_base_url: server.base_url.join("img/").unwrap(),
+            ..DownloaderConfig::default()
+        };
+        Downloader::new(vec![], &config)
+            .unwrap()
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 3,
+                base_delay: std::time::Duration::from_millis(1),
+                max_delay: std::time::Duration::from_millis(10),
+            })
+    }
+
+    #[test]
+    fn downloads_resume_from_the_server() {
+        let dir = test_directory();
+        let server = MockServer::start();
+        let downloader = mock_downloader(&server);
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(Some(1));
+        let url = server.base_url.join("media/2?enc=flac").unwrap();
+        let original = std::fs::read(std::path::Path::new("testdata").join("track.flac")).unwrap();
+        // a validator that still matches resumes, an outdated one starts over
+        for (artist, validator, first_byte) in [
+            ("Resumed", "\"track.flac\"", 2),
+            ("Restarted", "\"old.flac\"", 0),
+        ] {
+            let item = CollectionItem {
+                itype: CollectionItemKind::Track,
+                title: "Track".to_string(),
+                artist: artist.to_string(),
+                ..CollectionItem::default()
+            };
+            std::fs::create_dir_all(dir.join(artist)).unwrap();
+            std::fs::File::create(dir.join(artist).join(".incomplete")).unwrap();
+            let tempfile = dir.join(artist).join(".temporary.Artist - Track 2.flac");
+            std::fs::write(&tempfile, &original[..2]).unwrap();
+            std::fs::write(validator_path(&tempfile), validator).unwrap();
+            let mut media_download = downloader.get_media(url.as_str()).unwrap();
+            let mut observer = RecordingObserver::default();
+            handle_download_response(
+                &console.item(0),
+                &file_manager,
+                &item,
+                &mut media_download,
+                &mut observer,
+                None,
+            )
+            .unwrap();
+            assert!(observer.events.iter().all(|p| p.transferred > first_byte));
+            let downloaded = std::fs::read(dir.join(artist).join("Artist - Track 2.flac"));
+            assert_eq!(downloaded.unwrap(), original);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn verify_carries_on_past_unreadable_directories() {
+        let dir = test_directory();
+        let file_manager = FileManager::new(dir.clone());
+        let good = dir.join("Artist").join("Good");
+        let broken = dir.join("Artist").join("Broken");
+        std::fs::create_dir_all(&good).unwrap();
+        std::fs::create_dir_all(&broken).unwrap();
+        std::fs::write(good.join("file1.flac"), b"one\n").unwrap();
+        let mut manifest = Manifest::default();
+        manifest.files.insert(
+            "file1.flac".to_string(),
+            ManifestEntry::from_file(&good.join("file1.flac")).unwrap(),
+        );
+        manifest.files.insert(
+            "file2.flac".to_string(),
+            ManifestEntry {
+                size: 4,
+                sha256: "".to_string(),
+            },
+        );
+        manifest.save(&good).unwrap();
+        std::fs::write(broken.join(MANIFEST_FILENAME), b"{not json").unwrap();
+        let check = check_library(&file_manager).unwrap();
+        assert_eq!(check.checked, 2);
+        assert_eq!(
+            check.failed,
+            vec![(good, vec![VerifyFailure::Missing("file2.flac".to_string())])]
+        );
+        assert_eq!(check.unreadable, vec![broken]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replays_skip_media() {
+        let dir = test_directory();
+        let server = MockServer::start();
+        let download_url = server.base_url.join("download/1").unwrap().to_string();
+        let recording = dir.join("recording");
+        let config = DownloaderConfig {
+            base_url: server.base_url.clone(),
+            recorder: Some(std::sync::Arc::new(
+                Recorder::record(recording.clone()).unwrap(),
+            )),
+            ..DownloaderConfig::default()
+        };
+        Downloader::new(vec![], &config)
+            .unwrap()
+            .get_page(&download_url)
+            .unwrap();
+        let config = DownloaderConfig {
+            recorder: Some(std::sync::Arc::new(Recorder::replay(recording).unwrap())),
+            ..config
+        };
+        let downloader = Downloader::new(vec![], &config).unwrap();
+        let file_manager = FileManager::new(dir.join("music"));
+        let item = CollectionItem {
+            title: "Item".to_string(),
+            artist: "Artist".to_string(),
+            download_url: Some(download_url),
+            ..CollectionItem::default()
+        };
+        let outcome = process_item(
+            &Console::new(None).item(0),
+            &downloader,
+            &file_manager,
+            &SyncOptions {
+                cover_art: None,
+                ..SyncOptions::default()
+            },
+            &item,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome, ItemOutcome::Skipped);
+        assert!(!dir.join("music").join("Artist").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn sync_with_mock_server(
+        server: &MockServer,
+        directory: &std::path::Path,
+        include_hidden: bool,
+    ) -> Vec<ItemResult> {
+        let (results, listing) = try_sync_with_mock_server(server, directory, include_hidden);
+        listing.unwrap();
+        results
+    }
+
+    fn try_sync_with_mock_server(
+        server: &MockServer,
+        directory: &std::path::Path,
+        include_hidden: bool,
+    ) -> (Vec<ItemResult>, Result<(), ListingError>) {
+        let downloader = mock_downloader(server);
+        let home_page = downloader.get_page(downloader.base_url().as_str()).unwrap();
+        let (username, collection_link) = parse_home_page(&home_page).unwrap();
+        assert_eq!(username, "fan");
+        download_collection(
+            &downloader,
+            &collection_link,
+            &FileManager::new(directory.to_path_buf()),
+            &SyncOptions {
+                cover_art: Some(ArtSize::Medium),
+                include_hidden,
+                texts: true,
+                jobs: 2,
+                ..SyncOptions::default()
+            },
+        )
+    }
+
+    #[test]
+    fn listing_failures_keep_the_results() {
+        let dir = test_directory();
+        let server = MockServer::start();
+        server.inject(
+            "/api/fancollection/1/collection_items",
+            Fault::ServerError(100),
+        );
+        let (results, listing) = try_sync_with_mock_server(&server, &dir, false);
+        assert!(matches!(listing, Err(ListingError::BadRequest(_))));
+        // the items of the first page were still downloaded
+        let titles: Vec<&str> = results
+            .iter()
+            .map(|(_, item, _)| item.title.as_str())
+            .collect();
+        assert_eq!(titles, vec!["Album One", "Track Two"]);
+        for (_, _, result) in results.iter() {
+            assert_eq!(result.as_ref().unwrap(), &ItemOutcome::Downloaded);
+        }
+
+        let downloader = mock_downloader(&server);
+        server.inject(
+            "/api/fancollection/1/wishlist_items",
+            Fault::ServerError(100),
+        );
+        let collection_link = server.base_url.join("fan").unwrap();
+        assert!(matches!(
+            process_wishlist_link(&downloader, collection_link.as_str()),
+            Err(ListingError::BadRequest(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_listings_are_saved_for_diagnostics() {
+        let dir = test_directory();
+        let html = r#"<div id="pagedata" data-blob="{&quot;fan_data&quot;: {&quot;fan_id&quot;: 1}}"></div>"#;
+        let err =
+            parse_listing("https://bandcamp.com/fan?x=1", html, parse_collection_page).unwrap_err();
+        assert!(err.to_string().ends_with("in https://bandcamp.com/fan?x=1"));
+        assert_eq!(save_diagnostics(None, &err), None);
+        let message = save_diagnostics(Some(&dir), &err).unwrap();
+        assert!(message.starts_with("Page data saved to"));
+        let saved: Vec<std::path::PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(saved.len(), 1);
+        assert!(saved[0]
+            .to_string_lossy()
+            .ends_with("https_bandcamp.com_fan.json"));
+        let blob: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&saved[0]).unwrap()).unwrap();
+        assert_eq!(blob["fan_data"]["fan_id"], 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wishlist_end_to_end() {
+        let server = MockServer::start();
+        server.inject("/api/fancollection/1/wishlist_items", Fault::ServerError(1));
+        let downloader = mock_downloader(&server);
+        let collection_link = server.base_url.join("fan").unwrap();
+        let items = process_wishlist_link(&downloader, collection_link.as_str()).unwrap();
+        let titles: Vec<&str> = items.iter().map(|item| item.title.as_str()).collect();
+        assert_eq!(titles, vec!["Wanted Album", "Wanted \"Single\""]);
+
+        let mut csv = vec![];
+        write_wishlist(&mut csv, &items, &ExportFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            format!(
+                "artist,title,url,price,currency\n\
+                 Artist,Wanted Album,{0}album/5,8,USD\n\
+                 \"Band, The\",\"Wanted \"\"Single\"\"\",{0}track/6,,\n",
+                server.base_url
+            )
+        );
+        let mut json = vec![];
+        write_wishlist(&mut json, &items, &ExportFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[0]["artist"], "Artist");
+        assert_eq!(json[0]["price"], 8.0);
+        assert_eq!(json[1]["url"], format!("{}track/6", server.base_url));
+    }
+
+    #[test]
+    fn download_item_with_several_digital_items() {
+        let dir = test_directory();
+        let server = MockServer::start();
+        let downloader = mock_downloader(&server);
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(Some(1));
+        let options = SyncOptions {
+            cover_art: Some(ArtSize::Medium),
+            ..SyncOptions::default()
+        };
+        let item = CollectionItem {
+            itype: CollectionItemKind::Album,
+            title: "Bundle".to_string(),
+            artist: "Artist".to_string(),
+            download_url: server
+                .base_url
+                .join("download/9")
+                .unwrap()
+                .to_string()
+                .into(),
+            art_id: Some(4090),
+            ..CollectionItem::default()
+        };
+        let outcome = process_item(
+            &console.item(0),
+            &downloader,
+            &file_manager,
+            &options,
+            &item,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome, ItemOutcome::Downloaded);
+        for (title, art) in [
+            ("First Release", "art 4091 5"),
+            ("Second Release", "art 4092 5"),
+        ] {
+            let album = dir.join("Artist").join(title);
+            assert_eq!(std::fs::read(album.join("file1.flac")).unwrap(), b"one\n");
+            assert!(album.join(MANIFEST_FILENAME).exists());
+            assert_eq!(
+                std::fs::read(album.join("cover.jpg")).unwrap(),
+                art.as_bytes()
+            );
+        }
+        assert_eq!(
+            std::fs::read(
+                dir.join("Artist")
+                    .join("First Release")
+                    .join("extras")
+                    .join("Liner Notes.pdf")
+            )
+            .unwrap(),
+            b"extra Liner Notes.pdf"
+        );
+        assert!(!dir
+            .join("Artist")
+            .join("Second Release")
+            .join("extras")
+            .exists());
+        assert!(dir
+            .join("Other Artist")
+            .join("Artist - Track 2.flac")
+            .exists());
+        assert!(!dir.join("Artist").join("Bundle").exists());
+
+        let outcome = process_item(
+            &console.item(0),
+            &downloader,
+            &file_manager,
+            &options,
+            &item,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome, ItemOutcome::Skipped);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn packages_are_saved_as_their_digital_copy() {
+        let dir = test_directory();
+        let server = MockServer::start();
+        let downloader = mock_downloader(&server);
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(None);
+        let options = SyncOptions {
+            cover_art: Some(ArtSize::Medium),
+            extras: false,
+            ..SyncOptions::default()
+        };
+        let merch_only = CollectionItem {
+            itype: CollectionItemKind::Package,
+            title: "T-Shirt".to_string(),
+            artist: "Artist".to_string(),
+            art_id: Some(4010),
+            ..CollectionItem::default()
+        };
+        let outcome = process_item(
+            &console.item(0),
+            &downloader,
+            &file_manager,
+            &options,
+            &merch_only,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome, ItemOutcome::Skipped);
+        assert!(!dir.join("Artist").exists());
+
+        // the digital copy is saved under its own title, not the merch's
+        let vinyl = CollectionItem {
+            title: "Item (Vinyl LP)".to_string(),
+            download_url: server
+                .base_url
+                .join("download/1")
+                .unwrap()
+                .to_string()
+                .into(),
+            ..merch_only
+        };
+        for expected in [ItemOutcome::Downloaded, ItemOutcome::Skipped] {
+            let outcome = process_item(
+                &console.item(1),
+                &downloader,
+                &file_manager,
+                &options,
+                &vinyl,
+                None,
+            )
+            .unwrap();
+            assert_eq!(outcome, expected);
+        }
+        assert!(dir.join("Artist").join("Item").join("file1.flac").exists());
+        assert!(!dir.join("Artist").join("Item (Vinyl LP)").exists());
+        assert!(!dir.join("Artist").join("Item").join("extras").exists());
+
+        // merch whose download page offers no digital copy is skipped
+        let poster = CollectionItem {
+            title: "Poster".to_string(),
+            download_url: server
+                .base_url
+                .join("download/10")
+                .unwrap()
+                .to_string()
+                .into(),
+            ..vinyl
+        };
+        let outcome = process_item(
+            &console.item(2),
+            &downloader,
+            &file_manager,
+            &options,
+            &poster,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome, ItemOutcome::Skipped);
+        assert!(!dir.join("Artist").join("Poster").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn albums_without_download_url_fail() {
+        let dir = test_directory();
+        let server = MockServer::start();
+        let downloader = mock_downloader(&server);
+        let file_manager = FileManager::new(dir.clone());
+        let console = Console::new(None);
+        let album = CollectionItem {
+            title: "Item".to_string(),
+            artist: "Artist".to_string(),
+            ..CollectionItem::default()
+        };
+        let result = process_item(
+            &console.item(0),
+            &downloader,
+            &file_manager,
+            &SyncOptions::default(),
+            &album,
+            None,
+        );
+        assert!(matches!(result, Err(ItemError::MissingDownloadUrl)));
+        assert!(!dir.join("Artist").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bundles_are_expanded_into_releases() {
+        let dir = test_directory();
+        let server = MockServer::start();
+        let downloader = mock_downloader(&server);
+        let page_data = CollectionPageData {
+            fan_id: 1001,
+            last_token: String::new(),
+            collection_items: vec![
+                CollectionItem {
+                    itype: CollectionItemKind::Bundle,
+                    title: "Discography".to_string(),
+                    artist: "Artist".to_string(),
+                    download_url: server
+                        .base_url
+                        .join("download/9")
+                        .unwrap()
+                        .to_string()
+                        .into(),
+                    sale_item_id: 77,
+                    price: Some(20.0),
+                    ..CollectionItem::default()
+                },
+                CollectionItem {
+                    title: "Album One".to_string(),
+                    artist: "Artist".to_string(),
+                    download_url: server
+                        .base_url
+                        .join("download/1")
+                        .unwrap()
+                        .to_string()
+                        .into(),
+                    ..CollectionItem::default()
+                },
+            ],
+        };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        process_collection_items(&downloader, page_data, "unused", &sender).unwrap();
+        drop(sender);
+        let mut queued: Vec<(CollectionItem, Option<DigitalItem>)> = receiver.iter().collect();
+        let titles: Vec<(&str, Option<&str>, bool)> = queued
+            .iter()
+            .map(|(item, digital_item)| {
+                (
+                    item.title.as_str(),
+                    item.bundle.as_deref(),
+                    digital_item.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            titles,
+            vec![
+                ("First Release", Some("Discography"), true),
+                ("Second Release", Some("Discography"), true),
+                ("Loose Track", Some("Discography"), true),
+                ("Album One", None, false),
+            ]
+        );
+        let items: Vec<&CollectionItem> = queued.iter().map(|(item, _)| item).collect();
+        assert_eq!(items[2].itype, CollectionItemKind::Track);
+        // the purchase's ids and price are not the release's
+        assert_eq!(items[1].sale_item_id, 0);
+        assert_eq!(items[1].price, None);
+        assert_eq!(
+            items[0].item_url,
+            Some(format!("{}album/91", server.base_url))
+        );
+
+        // each release downloads only itself, like an album bought on its own,
+        // without fetching the bundle's download page again
+        server.inject("/download/9", Fault::ServerError(5));
+        let file_manager = FileManager::new(dir.clone());
+        let options = SyncOptions {
+            cover_art: None,
+            ..SyncOptions::default()
+        };
+        let console = Console::new(None);
+        let (second, second_digital) = queued.remove(1);
+        let outcome = process_item(
+            &console.item(1),
+            &downloader,
+            &file_manager,
+            &options,
+            &second,
+            second_digital,
+        )
+        .unwrap();
+        assert_eq!(outcome, ItemOutcome::Downloaded);
+        assert!(dir
+            .join("Artist")
+            .join("Second Release")
+            .join("file1.flac")
+            .exists());
+        assert!(!dir.join("Artist").join("First Release").exists());
+        assert!(!dir.join("Artist").join("Discography").exists());
+
+        // a release another job is downloading is left to that job
+        let (first, first_digital) = queued.remove(0);
+        let claim = file_manager.claim(&first).unwrap();
+        let outcome = process_item(
+            &console.item(0),
+            &downloader,
+            &file_manager,
+            &options,
+            &first,
+            first_digital,
+        )
+        .unwrap();
+        assert_eq!(outcome, ItemOutcome::Skipped);
+        assert!(!dir.join("Artist").join("First Release").exists());
+        drop(claim);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn download_collection_with_hidden_items() {
+        let dir = test_directory();
+        let server = MockServer::start();
+        server.inject("/api/fancollection/1/hidden_items", Fault::ServerError(1));
+        let results = sync_with_mock_server(&server, &dir, true);
+        let titles: Vec<&str> = results
+            .iter()
+            .map(|(_, item, _)| item.title.as_str())
+            .collect();
+        assert_eq!(
+            titles,
+            vec![
+                "Album One",
+                "Track Two",
+                "Album Three",
+                "Album Four",
+                "Hidden Album",
+                "Hidden Album Two"
+            ]
+        );
+        for (_, _, result) in results.iter() {
+            assert_eq!(result.as_ref().unwrap(), &ItemOutcome::Downloaded);
+        }
+        for album in [
+            dir.join("Artist").join("Hidden Album"),
+            dir.join("Other Artist").join("Hidden Album Two"),
+        ] {
+            assert_eq!(std::fs::read(album.join("file1.flac")).unwrap(), b"one\n");
+            assert!(album.join(MANIFEST_FILENAME).exists());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn download_collection_end_to_end() {
+        let dir = test_directory();
+        let server = MockServer::start();
+        server.inject(
+            "/api/fancollection/1/collection_items",
+            Fault::ServerError(1),
+        );
+        server.inject("/media/3", Fault::ServerError(2));
+        server.inject("/media/4", Fault::NoContentDisposition);
+        let results = sync_with_mock_server(&server, &dir, false);
+        let titles: Vec<&str> = results
+            .iter()
+            .map(|(_, item, _)| item.title.as_str())
+            .collect();
+        assert_eq!(
+            titles,
+            vec!["Album One", "Track Two", "Album Three", "Album Four"]
+        );
+        for (_, _, result) in results.iter().take(3) {
+            assert_eq!(result.as_ref().unwrap(), &ItemOutcome::Downloaded);
+        }
+        assert!(matches!(
+            results[3].2,
+            Err(ItemError::BadMedia(GetMediaError::NoContentDisposition(_)))
+        ));
+        for album in [
+            dir.join("Artist").join("Album One"),
+            dir.join("Other Artist").join("Album Three"),
+        ] {
+            assert_eq!(std::fs::read(album.join("file1.flac")).unwrap(), b"one\n");
+            assert_eq!(
+                std::fs::read(album.join("extras").join("Video.mp4")).unwrap(),
+                b"extra video.mp4"
+            );
+            let manifest = Manifest::load(&album).unwrap();
+            assert!(manifest.files.contains_key("extras/Video.mp4"));
+            assert_eq!(
+                std::fs::read(album.join("lyrics").join("01 - file1.txt")).unwrap(),
+                b"first line\nsecond line"
+            );
+            assert!(!album.join("lyrics").join("02 - file2.txt").exists());
+            assert_eq!(
+                std::fs::read(album.join("credits.txt")).unwrap(),
+                b"Recorded at home."
+            );
+            assert!(!album.join("about.txt").exists());
+            assert!(manifest.files.contains_key("credits.txt"));
+            assert!(album.join("cover.jpg").exists());
+            assert!(album.join("folder.jpg").exists());
+            assert!(!album.join(".incomplete").exists());
+        }
+        assert_eq!(
+            std::fs::read(dir.join("Artist").join("Artist - Track 2.flac")).unwrap(),
+            b"uwu\n"
+        );
+        assert_eq!(
+            std::fs::read(dir.join("Artist").join("Track Two.jpg")).unwrap(),
+            b"art 4002 5"
+        );
+        // art that is already there is left alone
+        std::fs::write(dir.join("Artist").join("Track Two.jpg"), b"mine").unwrap();
+        // as are texts, while those missing from a finished album are filled in
+        let album_one = dir.join("Artist").join("Album One");
+        let lyrics = album_one.join("lyrics").join("01 - file1.txt");
+        std::fs::write(&lyrics, b"mine").unwrap();
+        std::fs::remove_file(album_one.join("credits.txt")).unwrap();
+        let mut manifest = Manifest::load(&album_one).unwrap();
+        manifest.files.remove("credits.txt");
+        manifest.save(&album_one).unwrap();
+
+        // a second run skips finished items and tries the failed one again
+        server.inject("/media/4", Fault::TruncatedBody);
+        // extras saved by the failed run are not requested again
+        server.inject("/extra/video.mp4", Fault::ServerError(100));
+        let results = sync_with_mock_server(&server, &dir, false);
+        for (_, _, result) in results.iter().take(3) {
+            assert_eq!(result.as_ref().unwrap(), &ItemOutcome::Skipped);
+        }
+        assert!(matches!(results[3].2, Err(ItemError::BadDownload(_))));
+        // a failed transfer keeps what arrived, instead of being mistaken
+        // for an archive that can't be streamed and downloaded again in full
+        let partial = dir
+            .join("Other Artist")
+            .join("Album Four")
+            .join(".temporary.Album 4.zip");
+        let partial_length = std::fs::metadata(&partial).unwrap().len();
+        assert!(partial_length > 0);
+        assert!(partial_length < std::fs::metadata("testdata/archive.zip").unwrap().len());
+        assert!(server.ranges("/media/4").is_empty());
+        assert_eq!(
+            std::fs::read(dir.join("Artist").join("Track Two.jpg")).unwrap(),
+            b"mine"
+        );
+        assert_eq!(std::fs::read(&lyrics).unwrap(), b"mine");
+        assert_eq!(
+            std::fs::read(album_one.join("credits.txt")).unwrap(),
+            b"Recorded at home."
+        );
+        assert!(Manifest::load(&album_one)
+            .unwrap()
+            .files
+            .contains_key("credits.txt"));
+        assert!(dir
+            .join("Other Artist")
+            .join("Album Four")
+            .join(".incomplete")
+            .exists());
+
+        // and a third run resumes the archive where the transfer broke off
+        server.inject("/media/4", Fault::ServerError(0));
+        let results = sync_with_mock_server(&server, &dir, false);
+        assert_eq!(results[3].2.as_ref().unwrap(), &ItemOutcome::Downloaded);
+        assert_eq!(
+            server.ranges("/media/4"),
+            vec![format!("bytes={}-", partial_length)]
+        );
+        assert!(!partial.exists());
+        assert_eq!(
+            std::fs::read(
+                dir.join("Other Artist")
+                    .join("Album Four")
+                    .join("file1.flac")
+            )
+            .unwrap(),
+            b"one\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+pub const MANIFEST_FILENAME: &str = ".manifest.json";
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl ManifestEntry {
+    pub fn from_file(path: &std::path::Path) -> Result<Self, std::io::Error> {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = sha2::Sha256::new();
+        let size = std::io::copy(&mut file, &mut hasher)?;
+        Ok(ManifestEntry {
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+}
+
+/// The files that make up the completed items in a single directory
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    pub files: std::collections::BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyFailure {
+    Missing(String),
+    Changed(String),
+}
+
+impl VerifyFailure {
+    pub fn filename(&self) -> &str {
+        match self {
+            VerifyFailure::Missing(filename) => filename,
+            VerifyFailure::Changed(filename) => filename,
+        }
+    }
+}
+
+impl Manifest {
+    /// Load the manifest of `directory`, which is empty if there isn't one yet
+    pub fn load(directory: &std::path::Path) -> Result<Self, std::io::Error> {
+        match std::fs::read(directory.join(MANIFEST_FILENAME)) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, directory: &std::path::Path) -> Result<(), std::io::Error> {
+        let tempfile = directory.join(format!(".temporary.{}", MANIFEST_FILENAME));
+        std::fs::write(&tempfile, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(tempfile, directory.join(MANIFEST_FILENAME))?;
+        Ok(())
+    }
+
+    pub fn verify(
+        &self,
+        directory: &std::path::Path,
+    ) -> Result<Vec<VerifyFailure>, std::io::Error> {
+        let mut failures = vec![];
+        for (filename, entry) in self.files.iter() {
+            let path = directory.join(filename);
+            match std::fs::metadata(&path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    failures.push(VerifyFailure::Missing(filename.clone()));
+                }
+                Err(e) => return Err(e),
+                Ok(metadata) if metadata.len() != entry.size => {
+                    failures.push(VerifyFailure::Changed(filename.clone()));
+                }
+                Ok(_) => {
+                    if &ManifestEntry::from_file(&path)? != entry {
+                        failures.push(VerifyFailure::Changed(filename.clone()));
+                    }
+                }
+            }
+        }
+        Ok(failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::{Manifest, ManifestEntry, VerifyFailure};
+
+    #[test]
+    fn test_verify() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!(
+            "test-manifest-{}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64()
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        for name in ["a.flac", "b.flac", "c.flac"] {
+            std::fs::copy("testdata/track.flac", dir.join(name)).unwrap();
+        }
+        let mut manifest = Manifest::default();
+        for name in ["a.flac", "b.flac", "c.flac"] {
+            let entry = ManifestEntry::from_file(&dir.join(name)).unwrap();
+            manifest.files.insert(name.to_string(), entry);
+        }
+        assert_eq!(
+            manifest.files["a.flac"].sha256,
+            "3fb5311ff7066e902a92fe8b499a35ab7f1d45c4752d2e02eca897d2526e8681"
+        );
+        manifest.save(&dir).unwrap();
+        let manifest = Manifest::load(&dir).unwrap();
+        assert_eq!(manifest.verify(&dir).unwrap(), vec![]);
+        std::fs::remove_file(dir.join("a.flac")).unwrap();
+        std::fs::write(dir.join("b.flac"), b"bad\n").unwrap();
+        assert_eq!(
+            manifest.verify(&dir).unwrap(),
+            vec![
+                VerifyFailure::Missing("a.flac".to_string()),
+                VerifyFailure::Changed("b.flac".to_string()),
+            ]
+        );
+    }
+}
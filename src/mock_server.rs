@@ -0,0 +1,331 @@
+use std::io::{BufRead, Write};
+
+const FIXTURES: &str = "testdata/mock";
+
+/// Ways to make a response go wrong
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer with 500 this many times before answering properly
+    ServerError(usize),
+    /// Leave out the Content-Disposition header of media
+    NoContentDisposition,
+    /// Close the connection halfway through the body
+    TruncatedBody,
+    /// Serve a page instead of the media at this path until its status
+    /// has been asked for and answered "preparing" this many times
+    Preparing(usize),
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    truncated: bool,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+            truncated: false,
+        }
+    }
+
+    fn not_found() -> Self {
+        Response::new(404, "text/plain", b"not found".to_vec())
+    }
+}
+
+/// A stand-in for bandcamp.com that serves the fixtures in `testdata/mock`
+pub struct MockServer {
+    pub base_url: reqwest::Url,
+    /// Faults to inject for each path
+    faults: std::sync::Mutex<std::collections::HashMap<String, Fault>>,
+    /// The path and Range header of every request for part of a file
+    ranges: std::sync::Mutex<Vec<(String, String)>>,
+}
+
+impl MockServer {
+    /// Start serving on a free local port until the process exits
+    pub fn start() -> std::sync::Arc<Self> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let server = std::sync::Arc::new(MockServer {
+            base_url,
+            faults: std::sync::Mutex::new(std::collections::HashMap::new()),
+            ranges: std::sync::Mutex::new(vec![]),
+        });
+        let handler = server.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = handler.clone();
+                // a client hanging up early is not the server's problem
+                std::thread::spawn(move || handler.serve(stream));
+            }
+        });
+        server
+    }
+
+    pub fn inject(&self, path: &str, fault: Fault) {
+        self.faults.lock().unwrap().insert(path.to_string(), fault);
+    }
+
+    /// The Range headers sent for `path` so far
+    pub fn ranges(&self, path: &str) -> Vec<String> {
+        self.ranges
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(requested, _)| requested == path)
+            .map(|(_, range)| range.clone())
+            .collect()
+    }
+
+    fn serve(&self, stream: std::net::TcpStream) -> std::io::Result<()> {
+        let mut reader = std::io::BufReader::new(stream.try_clone()?);
+        let request = read_request(&mut reader)?;
+        if let Some(range) = request.header("Range") {
+            self.ranges
+                .lock()
+                .unwrap()
+                .push((request.path.clone(), range.to_string()));
+        }
+        let response = self.respond(&request);
+        write_response(stream, &response)
+    }
+
+    fn fixture(&self, name: &str) -> Vec<u8> {
+        std::fs::read_to_string(std::path::Path::new(FIXTURES).join(name))
+            .unwrap()
+            .replace("{base_url}", self.base_url.as_str())
+            .into_bytes()
+    }
+
+    fn respond(&self, request: &Request) -> Response {
+        let fault = {
+            let mut faults = self.faults.lock().unwrap();
+            match faults.get_mut(&request.path) {
+                Some(Fault::ServerError(0)) | Some(Fault::Preparing(0)) => None,
+                Some(Fault::ServerError(count)) => {
+                    *count -= 1;
+                    return Response::new(500, "text/plain", b"server error".to_vec());
+                }
+                Some(Fault::Preparing(_)) => {
+                    return Response::new(200, "text/html", b"<html>preparing</html>".to_vec());
+                }
+                fault => fault.cloned(),
+            }
+        };
+        let mut response = self.route(request);
+        match fault {
+            Some(Fault::NoContentDisposition) => {
+                response
+                    .headers
+                    .retain(|(name, _)| name != "Content-Disposition");
+            }
+            Some(Fault::TruncatedBody) => response.truncated = true,
+            _ => {}
+        }
+        response
+    }
+
+    fn route(&self, request: &Request) -> Response {
+        let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", [""]) => Response::new(200, "text/html", self.fixture("home.html")),
+            ("GET", ["fan"]) => Response::new(200, "text/html", self.fixture("collection.html")),
+            ("POST", ["api", "fancollection", "1", listing])
+                if ["collection_items", "hidden_items", "wishlist_items"].contains(listing) =>
+            {
+                let body: serde_json::Value = match serde_json::from_slice(&request.body) {
+                    Ok(body) => body,
+                    Err(_) => return Response::new(400, "text/plain", vec![]),
+                };
+                let token = body["older_than_token"].as_str().unwrap_or_default();
+                let name = format!("{}_{}.json", listing, token);
+                if !std::path::Path::new(FIXTURES).join(&name).exists() {
+                    return Response::not_found();
+                }
+                Response::new(200, "application/json", self.fixture(&name))
+            }
+            ("GET", ["album", _]) => Response::new(200, "text/html", self.fixture("album.html")),
+            // media that Bandcamp prepares on demand, as on its download servers
+            ("GET", ["download", "album"]) if request.query.contains("enc=flac") => {
+                let body = std::fs::read("testdata/archive.zip").unwrap();
+                let mut response = Response::new(200, "application/octet-stream", body);
+                response.headers.push((
+                    "Content-Disposition".to_string(),
+                    "attachment; filename=\"Prepared Album.zip\"".to_string(),
+                ));
+                response
+            }
+            ("GET", ["statdownload", name]) => {
+                let mut faults = self.faults.lock().unwrap();
+                let result = match faults.get_mut(&format!("/download/{}", name)) {
+                    Some(Fault::Preparing(count)) if *count > 0 => {
+                        *count -= 1;
+                        "preparing"
+                    }
+                    _ => "ok",
+                };
+                let status = format!("jsonp_callback({{\"result\": \"{}\"}})", result);
+                Response::new(200, "application/javascript", status.into_bytes())
+            }
+            ("GET", ["download", id]) => {
+                // download page 9 is a bundle's, offering several releases,
+                let fixture = match *id {
+                    "9" => "download_bundle.html",
+                    // and download page 10 offers nothing, like merch without a digital copy
+                    "10" => "download_empty.html",
+                    _ => "download.html",
+                };
+                let page = String::from_utf8(self.fixture(fixture))
+                    .unwrap()
+                    .replace("{media_url}", &format!("{}media/{}", self.base_url, id));
+                Response::new(200, "text/html", page.into_bytes())
+            }
+            ("GET", ["img", name]) => {
+                let art = name
+                    .strip_prefix('a')
+                    .and_then(|name| name.strip_suffix(".jpg"))
+                    .and_then(|name| name.split_once('_'));
+                match art {
+                    Some((art_id, size)) => Response::new(
+                        200,
+                        "image/jpeg",
+                        format!("art {} {}", art_id, size).into_bytes(),
+                    ),
+                    None => Response::not_found(),
+                }
+            }
+            ("GET", ["extra", name]) => {
+                let name = name.replace("%20", " ");
+                let mut response = Response::new(
+                    200,
+                    "application/octet-stream",
+                    format!("extra {}", name).into_bytes(),
+                );
+                response.headers.push((
+                    "Content-Disposition".to_string(),
+                    format!("attachment; filename=\"{}\"", name),
+                ));
+                response
+            }
+            ("GET", ["media", id]) => {
+                if !request.query.contains("enc=flac") {
+                    return Response::not_found();
+                }
+                let (source, filename) = match *id {
+                    "2" => ("track.flac", format!("Artist - Track {}.flac", id)),
+                    _ => ("archive.zip", format!("Album {}.zip", id)),
+                };
+                let body = std::fs::read(std::path::Path::new("testdata").join(source)).unwrap();
+                let etag = format!("\"{}\"", source);
+                // ranges are served unless If-Range names another version of the file
+                let start = request
+                    .header("Range")
+                    .and_then(|range| range.strip_prefix("bytes="))
+                    .and_then(|range| range.strip_suffix('-'))
+                    .and_then(|start| start.parse::<usize>().ok())
+                    .filter(|start| *start < body.len())
+                    .filter(|_| request.header("If-Range").is_none_or(|tag| tag == etag));
+                let mut response = match start {
+                    Some(start) => {
+                        let mut response =
+                            Response::new(206, "application/octet-stream", body[start..].to_vec());
+                        response.headers.push((
+                            "Content-Range".to_string(),
+                            format!("bytes {}-{}/{}", start, body.len() - 1, body.len()),
+                        ));
+                        response
+                    }
+                    None => Response::new(200, "application/octet-stream", body),
+                };
+                response.headers.push(("ETag".to_string(), etag));
+                response.headers.push((
+                    "Content-Disposition".to_string(),
+                    format!("attachment; filename=\"{}\"", filename),
+                ));
+                response
+            }
+            _ => Response::not_found(),
+        }
+    }
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> std::io::Result<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (path, query) = (path.to_string(), query.to_string());
+    let mut content_length = 0;
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    })
+}
+
+fn write_response(mut stream: std::net::TcpStream, response: &Response) -> std::io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in response.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    let body = if response.truncated {
+        &response.body[..response.body.len() / 2]
+    } else {
+        &response.body[..]
+    };
+    stream.write_all(body)?;
+    stream.flush()?;
+    stream.shutdown(std::net::Shutdown::Both)
+}
@@ -1,29 +1,110 @@
 use json_dotpath::DotPaths;
 use serde::Deserialize;
 
-use crate::types::{CollectionItem, CollectionItemKind, Encoding};
+use crate::types::{CollectionItem, CollectionItemKind, Encoding, WishlistItem};
+
+const SE_COL_TEXT: &str = "li#collection-main > a";
+const SE_TRALBUM_TEXT: &str = "script[data-tralbum]";
 
 lazy_static::lazy_static! {
     static ref RE_MB: regex::Regex = regex::Regex::new(r#"^([0-9.]+)([GgMmKk])[Bb]?$"#).unwrap();
-    static ref SE_COL: scraper::Selector = scraper::Selector::parse("li#collection-main > a").unwrap();
+    static ref SE_COL: scraper::Selector = scraper::Selector::parse(SE_COL_TEXT).unwrap();
     static ref SE_DIV_PAGEDATA: scraper::Selector = scraper::Selector::parse("div#pagedata").unwrap();
+    static ref SE_TRALBUM: scraper::Selector = scraper::Selector::parse(SE_TRALBUM_TEXT).unwrap();
 }
 
 #[derive(Debug)]
 pub enum ParsePageError {
-    NoHtmlElement(&'static scraper::Selector),
+    NoHtmlElement(&'static str),
     PageDataNotFound,
     PageDataNotJSON(serde_json::Error),
-    InvalidJSON(&'static str, json_dotpath::Error),
-    UnexpectedDownloadDigitalItemCount(usize),
+    /// The path that failed, and the keys found where it stopped resolving
+    InvalidJSON(&'static str, json_dotpath::Error, Vec<String>),
+    NoDigitalItems,
+    /// An error in the page at a URL, with the contents it was found in
+    InPage {
+        url: String,
+        contents: String,
+        error: Box<ParsePageError>,
+    },
+}
+
+impl std::fmt::Display for ParsePageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParsePageError::NoHtmlElement(selector) => {
+                write!(f, "page has no element matching {:?}", selector)
+            }
+            ParsePageError::PageDataNotFound => write!(f, "page has no data blob"),
+            ParsePageError::PageDataNotJSON(e) => write!(f, "page data is not JSON: {}", e),
+            ParsePageError::InvalidJSON(path, e, keys) => {
+                write!(f, "page data has no valid {:?}: {}", path, e)?;
+                if !keys.is_empty() {
+                    write!(f, " (found keys: {})", keys.join(", "))?;
+                }
+                Ok(())
+            }
+            ParsePageError::NoDigitalItems => write!(f, "download page has no digital items"),
+            ParsePageError::InPage { url, error, .. } => write!(f, "{} in {}", error, url),
+        }
+    }
 }
 
+impl std::error::Error for ParsePageError {}
+
 impl From<serde_json::Error> for ParsePageError {
     fn from(err: serde_json::Error) -> Self {
         ParsePageError::PageDataNotJSON(err)
     }
 }
 
+impl ParsePageError {
+    /// Attach the page the error was found in, so that its data can be saved for bug reports
+    pub fn in_page(self, url: &str, contents: &str) -> ParsePageError {
+        ParsePageError::InPage {
+            url: url.to_string(),
+            contents: contents.to_string(),
+            error: Box::new(self),
+        }
+    }
+}
+
+/// The data blob of a page, or the whole page if it has none, with the extension it is saved under
+pub fn page_data(contents: &str) -> (String, &'static str) {
+    let blob = parse_data_blob(&scraper::Html::parse_document(contents))
+        .ok()
+        .and_then(|blob| serde_json::to_string_pretty(&blob).ok());
+    match blob {
+        Some(blob) => (blob, "json"),
+        None if contents.trim_start().starts_with('{') => (contents.to_string(), "json"),
+        None => (contents.to_string(), "html"),
+    }
+}
+
+/// The keys of the deepest object or array on `path` that exists in `value`
+fn neighbouring_keys(value: &serde_json::Value, path: &str) -> Vec<String> {
+    let mut current = value;
+    for segment in path.split('.') {
+        let next = match current {
+            serde_json::Value::Object(map) => map.get(segment),
+            serde_json::Value::Array(items) => segment
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| items.get(index)),
+            _ => None,
+        };
+        match next {
+            Some(next) if next.is_object() || next.is_array() => current = next,
+            _ => break,
+        }
+    }
+    match current {
+        serde_json::Value::Object(map) => map.keys().cloned().collect(),
+        serde_json::Value::Array(items) => (0..items.len()).map(|i| i.to_string()).collect(),
+        _ => vec![],
+    }
+}
+
 fn parse_data_blob(scraper: &scraper::html::Html) -> Result<serde_json::Value, ParsePageError> {
     let div = scraper
         .select(&SE_DIV_PAGEDATA)
@@ -40,11 +121,14 @@ fn parse_data_blob(scraper: &scraper::html::Html) -> Result<serde_json::Value, P
 macro_rules! page_data_dot_get {
     ($a:expr, $b:expr) => {
         $b.dot_get($a)
-            .map_err(|e| ParsePageError::InvalidJSON($a, e))?
-            .ok_or(ParsePageError::InvalidJSON(
-                $a,
-                json_dotpath::Error::BadPathElement,
-            ))?
+            .map_err(|e| ParsePageError::InvalidJSON($a, e, neighbouring_keys(&$b, $a)))?
+            .ok_or_else(|| {
+                ParsePageError::InvalidJSON(
+                    $a,
+                    json_dotpath::Error::BadPathElement,
+                    neighbouring_keys(&$b, $a),
+                )
+            })?
     };
 }
 
@@ -53,35 +137,81 @@ pub fn parse_home_page(html: &str) -> Result<(String, String), ParsePageError> {
     let collection_a = html
         .select(&SE_COL)
         .next()
-        .ok_or(ParsePageError::NoHtmlElement(&SE_COL))?;
+        .ok_or(ParsePageError::NoHtmlElement(SE_COL_TEXT))?;
     let href = match collection_a.value().attr("href") {
         Some(attr) => Ok(attr.to_string()),
-        None => Err(ParsePageError::NoHtmlElement(&SE_COL)),
+        None => Err(ParsePageError::NoHtmlElement(SE_COL_TEXT)),
     }?;
     let value = parse_data_blob(&html)?;
     let username: String = page_data_dot_get!("identities.fan.username", value);
     Ok((username, href))
 }
 
-#[allow(dead_code)]
+/// An item of one of the fan's listings, as in the item cache of the collection page
+/// and in the pages of the API; only purchases have the sale fields
 #[derive(Debug, Deserialize)]
-pub struct ItemCacheCollectionItem {
+pub struct ItemCacheItem {
     #[serde(alias = "item_title")]
     title: String,
     #[serde(alias = "band_name")]
     artist: String,
-    #[serde(alias = "tralbum_type")]
+    #[serde(default)]
     tralbum_type: String,
-    #[serde(alias = "tralbum_id")]
+    #[serde(default)]
     tralbum_id: u64,
-    #[serde(alias = "item_type")]
     item_type: CollectionItemKind,
-    #[serde(alias = "item_id")]
+    #[serde(default)]
     item_id: u64,
-    #[serde(alias = "sale_item_id")]
+    #[serde(default)]
     sale_item_id: u64,
-    #[serde(alias = "sale_item_type")]
+    #[serde(default)]
     sale_item_type: String,
+    #[serde(default)]
+    band_id: Option<u64>,
+    #[serde(default)]
+    item_url: Option<String>,
+    #[serde(default, alias = "item_art_id")]
+    art_id: Option<u64>,
+    #[serde(default)]
+    purchased: Option<String>,
+    #[serde(default)]
+    price: Option<f64>,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    genre_id: Option<u64>,
+}
+
+impl ItemCacheItem {
+    /// The key of this item's download page in `redownload_urls`
+    fn redownload_key(&self) -> String {
+        format!("{}{}", self.sale_item_type, self.sale_item_id)
+    }
+
+    fn into_collection_item(self, download_url: Option<String>) -> CollectionItem {
+        CollectionItem {
+            itype: self.item_type,
+            title: self.title,
+            artist: self.artist,
+            download_url,
+            item_id: self.item_id,
+            tralbum_id: self.tralbum_id,
+            tralbum_type: self.tralbum_type,
+            sale_item_id: self.sale_item_id,
+            sale_item_type: self.sale_item_type,
+            band_id: self.band_id,
+            item_url: self.item_url,
+            art_id: self.art_id,
+            purchased: self.purchased,
+            price: self.price,
+            currency: self.currency,
+            label: self.label,
+            genre_id: self.genre_id,
+            bundle: None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -91,10 +221,28 @@ pub struct CollectionPageData {
     pub collection_items: Vec<CollectionItem>,
 }
 
+/// The cached items in the order the page lists them, with their download pages,
+/// leaving out any that the cache has no entry for
+fn sequence_items(
+    sequence: &[String],
+    mut cache: std::collections::HashMap<String, ItemCacheItem>,
+    redownload_urls: &mut std::collections::HashMap<String, String>,
+) -> Vec<CollectionItem> {
+    sequence
+        .iter()
+        .filter_map(|seq| cache.remove(seq))
+        .map(|item| {
+            // merch without a digital copy has nothing to download
+            let url = redownload_urls.remove(&item.redownload_key());
+            item.into_collection_item(url)
+        })
+        .collect()
+}
+
 pub fn parse_collection_page(html: &str) -> Result<CollectionPageData, ParsePageError> {
     let html = scraper::Html::parse_document(html);
     let value = parse_data_blob(&html)?;
-    let mut collection: std::collections::HashMap<String, ItemCacheCollectionItem> =
+    let collection: std::collections::HashMap<String, ItemCacheItem> =
         page_data_dot_get!("item_cache.collection", value);
     let mut redownload_urls: std::collections::HashMap<String, String> =
         page_data_dot_get!("collection_data.redownload_urls", value);
@@ -102,21 +250,42 @@ pub fn parse_collection_page(html: &str) -> Result<CollectionPageData, ParsePage
     let sequence: Vec<String> = page_data_dot_get!("collection_data.sequence", value);
     let fan_id: u64 = page_data_dot_get!("fan_data.fan_id", value);
     let last_token: String = page_data_dot_get!("collection_data.last_token", value);
-    let collection_items: Vec<CollectionItem> = sequence
-        .iter()
-        .map(|seq| {
-            let item = collection.remove(seq).unwrap();
-            let url = redownload_urls
-                .remove(&format!("{}{}", item.sale_item_type, item.sale_item_id))
-                .unwrap();
-            CollectionItem {
-                itype: item.item_type,
-                title: item.title,
-                artist: item.artist,
-                download_url: url,
-            }
-        })
-        .collect();
+    let collection_items = sequence_items(&sequence, collection, &mut redownload_urls);
+    Ok(CollectionPageData {
+        fan_id,
+        last_token,
+        collection_items,
+    })
+}
+
+/// Read the first page of the items the fan has hidden from their public collection,
+/// which is embedded in the collection page, or nothing if the page has none
+pub fn parse_hidden_items_page(html: &str) -> Result<CollectionPageData, ParsePageError> {
+    let html = scraper::Html::parse_document(html);
+    let value = parse_data_blob(&html)?;
+    let fan_id: u64 = page_data_dot_get!("fan_data.fan_id", value);
+    let sequence: Vec<String> = match value.dot_get("hidden_data.sequence").map_err(|e| {
+        ParsePageError::InvalidJSON(
+            "hidden_data.sequence",
+            e,
+            neighbouring_keys(&value, "hidden_data.sequence"),
+        )
+    })? {
+        Some(sequence) => sequence,
+        None => {
+            return Ok(CollectionPageData {
+                fan_id,
+                last_token: String::new(),
+                collection_items: vec![],
+            })
+        }
+    };
+    let hidden: std::collections::HashMap<String, ItemCacheItem> =
+        page_data_dot_get!("item_cache.hidden", value);
+    let mut redownload_urls: std::collections::HashMap<String, String> =
+        page_data_dot_get!("collection_data.redownload_urls", value);
+    let last_token: String = page_data_dot_get!("hidden_data.last_token", value);
+    let collection_items = sequence_items(&sequence, hidden, &mut redownload_urls);
     Ok(CollectionPageData {
         fan_id,
         last_token,
@@ -124,42 +293,87 @@ pub fn parse_collection_page(html: &str) -> Result<CollectionPageData, ParsePage
     })
 }
 
+/// A page of one of the fan's listings from the API
 #[derive(Deserialize)]
-struct CollectionJSONObject {
-    items: Vec<ItemCacheCollectionItem>,
+struct ListingJSONObject {
+    items: Vec<ItemCacheItem>,
     last_token: String,
     more_available: bool,
+    /// Only the listings of purchases have these
+    #[serde(default)]
     redownload_urls: std::collections::HashMap<String, String>,
 }
 
-pub struct CollectionJSON {
+pub struct ListingJSON<T> {
     pub more_available: bool,
     pub last_token: String,
-    pub collection_items: Vec<CollectionItem>,
+    pub items: Vec<T>,
 }
 
-pub fn parse_collection_json(data: &str) -> Result<CollectionJSON, serde_json::Error> {
-    let mut collection: CollectionJSONObject = serde_json::from_str(data)?;
-    let collection_items: Vec<CollectionItem> = collection
+pub fn parse_collection_json(data: &str) -> Result<ListingJSON<CollectionItem>, serde_json::Error> {
+    let mut collection: ListingJSONObject = serde_json::from_str(data)?;
+    let items: Vec<CollectionItem> = collection
         .items
         .into_iter()
         .map(|item| {
-            let url = collection
-                .redownload_urls
-                .remove(&format!("{}{}", item.sale_item_type, item.sale_item_id))
-                .unwrap();
-            CollectionItem {
-                itype: item.item_type,
-                title: item.title,
-                artist: item.artist,
-                download_url: url,
-            }
+            let url = collection.redownload_urls.remove(&item.redownload_key());
+            item.into_collection_item(url)
         })
         .collect();
-    Ok(CollectionJSON {
+    Ok(ListingJSON {
         more_available: collection.more_available,
         last_token: collection.last_token,
-        collection_items,
+        items,
+    })
+}
+
+impl From<ItemCacheItem> for WishlistItem {
+    fn from(item: ItemCacheItem) -> Self {
+        WishlistItem {
+            itype: item.item_type,
+            title: item.title,
+            artist: item.artist,
+            url: item.item_url,
+            price: item.price,
+            currency: item.currency,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WishlistPageData {
+    pub fan_id: u64,
+    pub last_token: String,
+    pub wishlist_items: Vec<WishlistItem>,
+}
+
+/// Read the first page of the wishlist, which is embedded in the collection page
+pub fn parse_wishlist_page(html: &str) -> Result<WishlistPageData, ParsePageError> {
+    let html = scraper::Html::parse_document(html);
+    let value = parse_data_blob(&html)?;
+    let mut wishlist: std::collections::HashMap<String, ItemCacheItem> =
+        page_data_dot_get!("item_cache.wishlist", value);
+    let sequence: Vec<String> = page_data_dot_get!("wishlist_data.sequence", value);
+    let fan_id: u64 = page_data_dot_get!("fan_data.fan_id", value);
+    let last_token: String = page_data_dot_get!("wishlist_data.last_token", value);
+    let wishlist_items: Vec<WishlistItem> = sequence
+        .iter()
+        .filter_map(|seq| wishlist.remove(seq))
+        .map(WishlistItem::from)
+        .collect();
+    Ok(WishlistPageData {
+        fan_id,
+        last_token,
+        wishlist_items,
+    })
+}
+
+pub fn parse_wishlist_json(data: &str) -> Result<ListingJSON<WishlistItem>, serde_json::Error> {
+    let wishlist: ListingJSONObject = serde_json::from_str(data)?;
+    Ok(ListingJSON {
+        more_available: wishlist.more_available,
+        last_token: wishlist.last_token,
+        items: wishlist.items.into_iter().map(WishlistItem::from).collect(),
     })
 }
 
@@ -196,24 +410,168 @@ pub struct DownloadOption {
     pub url: String,
 }
 
-pub fn parse_download_page(html: &str) -> Result<std::vec::Vec<DownloadOption>, ParsePageError> {
+#[derive(Deserialize)]
+struct DigitalItemObject {
+    title: String,
+    #[serde(default)]
+    artist: Option<String>,
+    #[serde(default, rename = "type")]
+    item_type: Option<String>,
+    #[serde(default)]
+    art_id: Option<u64>,
+    #[serde(default, alias = "page_url")]
+    item_url: Option<String>,
+    downloads: std::collections::HashMap<String, DownloadOption>,
+    #[serde(default, alias = "bonus_items")]
+    extras: Vec<ExtraItem>,
+}
+
+/// A bonus file offered next to the audio, such as a PDF booklet or a video
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExtraItem {
+    pub title: String,
+    #[serde(alias = "download_url")]
+    pub url: String,
+}
+
+/// One release offered on a download page, of which bundles and gifts have several
+#[derive(Debug)]
+pub struct DigitalItem {
+    pub title: String,
+    pub artist: Option<String>,
+    pub itype: CollectionItemKind,
+    pub art_id: Option<u64>,
+    /// The release's own page, with its track listing
+    pub item_url: Option<String>,
+    pub downloads: Vec<DownloadOption>,
+    pub extras: Vec<ExtraItem>,
+}
+
+impl From<DigitalItemObject> for DigitalItem {
+    fn from(item: DigitalItemObject) -> Self {
+        DigitalItem {
+            title: item.title,
+            artist: item.artist,
+            itype: match item.item_type.as_deref() {
+                Some("t") | Some("track") => CollectionItemKind::Track,
+                _ => CollectionItemKind::Album,
+            },
+            art_id: item.art_id,
+            item_url: item.item_url,
+            downloads: item.downloads.into_values().collect(),
+            extras: item.extras,
+        }
+    }
+}
+
+pub fn parse_download_page(html: &str) -> Result<Vec<DigitalItem>, ParsePageError> {
     let html = scraper::Html::parse_document(html);
     let value = parse_data_blob(&html)?;
-    let digital_items: std::vec::Vec<serde_json::Value> =
-        page_data_dot_get!("digital_items", value);
-    if digital_items.len() != 1 {
-        return Err(ParsePageError::UnexpectedDownloadDigitalItemCount(
-            digital_items.len(),
-        ));
+    let digital_items: Vec<DigitalItemObject> = page_data_dot_get!("digital_items", value);
+    if digital_items.is_empty() {
+        return Err(ParsePageError::NoDigitalItems);
+    }
+    Ok(digital_items.into_iter().map(DigitalItem::from).collect())
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct TrackInfo {
+    #[serde(alias = "track_num")]
+    pub number: Option<u32>,
+    pub title: String,
+    /// In seconds
+    pub duration: Option<f64>,
+    /// Unreleased tracks of a pre-order are listed, but not downloadable
+    pub is_downloadable: Option<bool>,
+    #[serde(default)]
+    pub lyrics: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct AlbumPageData {
+    pub tracks: Vec<TrackInfo>,
+    pub credits: Option<String>,
+    /// The description of the album
+    pub about: Option<String>,
+}
+
+fn parse_tralbum_data(scraper: &scraper::html::Html) -> Result<serde_json::Value, ParsePageError> {
+    let script = scraper
+        .select(&SE_TRALBUM)
+        .next()
+        .ok_or(ParsePageError::NoHtmlElement(SE_TRALBUM_TEXT))?;
+    let blob = script
+        .value()
+        .attr("data-tralbum")
+        .ok_or(ParsePageError::PageDataNotFound)?;
+    let value = serde_json::from_str(blob)?;
+    Ok(value)
+}
+
+pub fn parse_album_page(html: &str) -> Result<AlbumPageData, ParsePageError> {
+    let html = scraper::Html::parse_document(html);
+    let value = parse_tralbum_data(&html)?;
+    let tracks: Vec<TrackInfo> = page_data_dot_get!("trackinfo", value);
+    Ok(AlbumPageData {
+        tracks,
+        credits: parse_album_text(&value, "current.credits"),
+        about: parse_album_text(&value, "current.about"),
+    })
+}
+
+/// A text of the album, which many albums leave out or empty
+fn parse_album_text(value: &serde_json::Value, path: &str) -> Option<String> {
+    value
+        .dot_get::<String>(path)
+        .ok()
+        .flatten()
+        .filter(|text| !text.trim().is_empty())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DownloadStatus {
+    /// The file can be fetched, possibly from a new URL
+    Ready(Option<String>),
+    /// Bandcamp is still preparing the file
+    Preparing,
+    /// Bandcamp gave up preparing the file
+    Failed(String),
+}
+
+#[derive(Deserialize)]
+struct DownloadStatusObject {
+    result: String,
+    download_url: Option<String>,
+    errortype: Option<String>,
+}
+
+/// Parse the response of a `statdownload` request, which may be wrapped in a JSONP callback
+pub fn parse_download_status(data: &str) -> Result<DownloadStatus, ParsePageError> {
+    let data = data.trim();
+    let json = match (data.find('('), data.rfind(')')) {
+        (Some(start), Some(end)) if !data.starts_with('{') && start < end => &data[start + 1..end],
+        _ => data,
+    };
+    let status: DownloadStatusObject = serde_json::from_str(json)?;
+    match status.result.as_str() {
+        "ok" => Ok(DownloadStatus::Ready(status.download_url)),
+        "err" => Ok(DownloadStatus::Failed(
+            status
+                .errortype
+                .unwrap_or_else(|| "unknown error".to_string()),
+        )),
+        _ => Ok(DownloadStatus::Preparing),
     }
-    let downloads: std::collections::HashMap<String, DownloadOption> =
-        page_data_dot_get!("digital_items.0.downloads", value);
-    Ok(downloads.into_values().collect())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::parser::deserialize_megabytes;
+    use crate::parser::{
+        deserialize_megabytes, page_data, parse_album_page, parse_collection_json,
+        parse_collection_page, parse_download_page, parse_download_status, parse_hidden_items_page,
+        parse_wishlist_json, parse_wishlist_page, DownloadStatus, ParsePageError,
+    };
+    use crate::types::{CollectionItemKind, Encoding};
     use serde::Deserialize;
     use serde_test::{assert_de_tokens, Token};
 
@@ -246,4 +604,208 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_parse_download_status() {
+        assert_eq!(
+            parse_download_status(r#"{"result":"ok","download_url":"https://example.com/a"}"#)
+                .unwrap(),
+            DownloadStatus::Ready(Some("https://example.com/a".to_string()))
+        );
+        assert_eq!(
+            parse_download_status(r#"cb({"result":"ok"});"#).unwrap(),
+            DownloadStatus::Ready(None)
+        );
+        assert_eq!(
+            parse_download_status(r#"{"result":"retry","retry_url":"https://example.com/r"}"#)
+                .unwrap(),
+            DownloadStatus::Preparing
+        );
+        assert_eq!(
+            parse_download_status(r#"{"result":"err","errortype":"ExpiredFreebieError"}"#).unwrap(),
+            DownloadStatus::Failed("ExpiredFreebieError".to_string())
+        );
+        assert!(parse_download_status("<html></html>").is_err());
+    }
+
+    #[test]
+    fn test_parse_collection_json() {
+        let data = std::fs::read_to_string("testdata/mock/collection_items_token1.json").unwrap();
+        let collection = parse_collection_json(&data).unwrap();
+        assert!(collection.more_available);
+        assert_eq!(collection.last_token, "token2");
+        let item = &collection.items[0];
+        assert_eq!(item.itype, CollectionItemKind::Album);
+        assert_eq!(item.title, "Album Three");
+        assert_eq!(item.download_url.as_deref(), Some("{base_url}download/3"));
+        assert_eq!((item.item_id, item.tralbum_id), (3, 3));
+        assert_eq!(
+            (item.sale_item_type.as_str(), item.sale_item_id),
+            ("p", 103)
+        );
+        assert_eq!(item.band_id, Some(3001));
+        assert_eq!(item.art_id, Some(4003));
+        assert_eq!(item.purchased.as_deref(), Some("07 Nov 2022 16:23:00 GMT"));
+        assert_eq!(item.price, Some(7.0));
+        assert_eq!(item.currency.as_deref(), Some("USD"));
+        assert_eq!(item.label.as_deref(), Some("Some Label"));
+        assert_eq!(item.genre_id, Some(10));
+    }
+
+    #[test]
+    fn test_parse_collection_json_packages() {
+        let data = r#"{
+            "items": [{
+                "item_title": "Album on Vinyl", "band_name": "Artist", "tralbum_type": "a",
+                "tralbum_id": 5, "item_type": "package", "item_id": 5,
+                "sale_item_id": 105, "sale_item_type": "p"
+            }],
+            "more_available": false,
+            "last_token": "token",
+            "redownload_urls": {}
+        }"#;
+        let collection = parse_collection_json(data).unwrap();
+        let item = &collection.items[0];
+        assert_eq!(item.itype, CollectionItemKind::Package);
+        assert_eq!(item.download_url, None);
+    }
+
+    #[test]
+    fn test_parse_album_page() {
+        let html = std::fs::read_to_string("testdata/mock/album.html").unwrap();
+        let album = parse_album_page(&html).unwrap();
+        let tracks: Vec<(Option<u32>, &str)> = album
+            .tracks
+            .iter()
+            .map(|track| (track.number, track.title.as_str()))
+            .collect();
+        assert_eq!(tracks, vec![(Some(1), "file1"), (Some(2), "file2")]);
+        assert_eq!(album.tracks[0].duration, Some(61.5));
+        assert_eq!(
+            album.tracks[0].lyrics.as_deref(),
+            Some("first line\nsecond line")
+        );
+        assert_eq!(album.tracks[1].lyrics, None);
+        assert_eq!(album.credits.as_deref(), Some("Recorded at home."));
+        assert_eq!(album.about, None);
+    }
+
+    #[test]
+    fn test_parse_wishlist() {
+        let html = std::fs::read_to_string("testdata/mock/collection.html").unwrap();
+        let page = parse_wishlist_page(&html).unwrap();
+        assert_eq!(page.fan_id, 1001);
+        assert_eq!(page.last_token, "wtoken1");
+        assert_eq!(page.wishlist_items.len(), 1);
+        assert_eq!(page.wishlist_items[0].title, "Wanted Album");
+        assert_eq!(page.wishlist_items[0].price, Some(8.0));
+
+        let data = std::fs::read_to_string("testdata/mock/wishlist_items_wtoken1.json").unwrap();
+        let wishlist = parse_wishlist_json(&data).unwrap();
+        assert!(!wishlist.more_available);
+        let item = &wishlist.items[0];
+        assert_eq!(item.itype, CollectionItemKind::Track);
+        assert_eq!(item.artist, "Band, The");
+        assert_eq!(item.url.as_deref(), Some("{base_url}track/6"));
+        assert_eq!((item.price, item.currency.as_deref()), (None, None));
+    }
+
+    #[test]
+    fn test_parse_hidden_items_page() {
+        let html = std::fs::read_to_string("testdata/mock/collection.html").unwrap();
+        let page = parse_hidden_items_page(&html).unwrap();
+        assert_eq!(page.fan_id, 1001);
+        assert_eq!(page.last_token, "htoken1");
+        let titles: Vec<&str> = page
+            .collection_items
+            .iter()
+            .map(|item| item.title.as_str())
+            .collect();
+        assert_eq!(titles, vec!["Hidden Album"]);
+        assert_eq!(
+            page.collection_items[0].download_url.as_deref(),
+            Some("{base_url}download/7")
+        );
+
+        let html = r#"<div id="pagedata" data-blob="{&quot;fan_data&quot;: {&quot;fan_id&quot;: 1}}"></div>"#;
+        let page = parse_hidden_items_page(html).unwrap();
+        assert!(page.collection_items.is_empty());
+
+        // an item the sequence names but the cache leaves out is skipped
+        let html = r#"<div id="pagedata" data-blob="{&quot;fan_data&quot;: {&quot;fan_id&quot;: 1}, &quot;hidden_data&quot;: {&quot;sequence&quot;: [&quot;a1&quot;], &quot;last_token&quot;: &quot;&quot;}, &quot;item_cache&quot;: {&quot;hidden&quot;: {}}, &quot;collection_data&quot;: {&quot;redownload_urls&quot;: {}}}"></div>"#;
+        let page = parse_hidden_items_page(html).unwrap();
+        assert!(page.collection_items.is_empty());
+    }
+
+    #[test]
+    fn test_parse_download_page() {
+        let html = std::fs::read_to_string("testdata/mock/download_bundle.html").unwrap();
+        let items = parse_download_page(&html).unwrap();
+        let titles: Vec<(&str, Option<&str>, &CollectionItemKind)> = items
+            .iter()
+            .map(|item| (item.title.as_str(), item.artist.as_deref(), &item.itype))
+            .collect();
+        assert_eq!(
+            titles,
+            vec![
+                ("First Release", Some("Artist"), &CollectionItemKind::Album),
+                ("Second Release", Some("Artist"), &CollectionItemKind::Album),
+                (
+                    "Loose Track",
+                    Some("Other Artist"),
+                    &CollectionItemKind::Track
+                ),
+            ]
+        );
+        assert_eq!(items[0].art_id, Some(4091));
+        assert_eq!(items[1].downloads.len(), 1);
+        assert_eq!(items[1].downloads[0].encoding, Encoding::Flac);
+        let extras: Vec<(&str, &str)> = items[0]
+            .extras
+            .iter()
+            .map(|extra| (extra.title.as_str(), extra.url.as_str()))
+            .collect();
+        assert_eq!(
+            extras,
+            vec![("Liner Notes", "{base_url}extra/Liner%20Notes.pdf")]
+        );
+        assert!(items[1].extras.is_empty());
+
+        assert_eq!(
+            serde_json::from_str::<CollectionItemKind>(r#""band""#).unwrap(),
+            CollectionItemKind::Bundle
+        );
+
+        let html = r#"<div id="pagedata" data-blob="{&quot;digital_items&quot;: []}"></div>"#;
+        assert!(matches!(
+            parse_download_page(html),
+            Err(ParsePageError::NoDigitalItems)
+        ));
+    }
+
+    #[test]
+    fn test_page_error_diagnostics() {
+        let html = r#"<div id="pagedata" data-blob="{&quot;fan_data&quot;: {&quot;fan_id&quot;: 1}, &quot;item_cache&quot;: {&quot;collection&quot;: {}}, &quot;collection_data&quot;: {&quot;redownload_urls&quot;: {}, &quot;sequence_v2&quot;: []}}"></div>"#;
+        let err = parse_collection_page(html).unwrap_err();
+        match &err {
+            ParsePageError::InvalidJSON(path, _, keys) => {
+                assert_eq!(*path, "collection_data.sequence");
+                assert_eq!(keys, &vec!["redownload_urls", "sequence_v2"]);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        let err = err.in_page("https://bandcamp.com/fan?x=1", html);
+        let message = err.to_string();
+        assert!(message.contains("\"collection_data.sequence\""));
+        assert!(message.contains("found keys: redownload_urls, sequence_v2"));
+        assert!(message.contains("in https://bandcamp.com/fan?x=1"));
+
+        let (data, extension) = page_data(html);
+        assert_eq!(extension, "json");
+        let blob: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(blob["fan_data"]["fan_id"], 1);
+        assert_eq!(page_data(r#"{"ok": 1}"#).1, "json");
+        assert_eq!(page_data("<html></html>").1, "html");
+    }
 }
@@ -0,0 +1,188 @@
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// Bytes of the file that are done, including any resumed prefix
+    pub transferred: u64,
+    pub total: u64,
+    /// Bytes per second over this transfer
+    pub rate: f64,
+    pub eta: Option<std::time::Duration>,
+}
+
+pub trait ProgressObserver {
+    fn on_progress(&mut self, progress: &Progress);
+    fn on_finish(&mut self, progress: &Progress);
+}
+
+pub struct NoProgress;
+
+impl ProgressObserver for NoProgress {
+    fn on_progress(&mut self, _progress: &Progress) {}
+    fn on_finish(&mut self, _progress: &Progress) {}
+}
+
+/// Draws progress on a bar, which is cleared away when the transfer ends,
+/// however it ends
+pub struct BarProgress(pub indicatif::ProgressBar);
+
+impl Drop for BarProgress {
+    fn drop(&mut self) {
+        // a failed transfer never reports its finish, and would leave its bar behind
+        if !self.0.is_finished() {
+            self.0.finish_and_clear();
+        }
+    }
+}
+
+impl ProgressObserver for BarProgress {
+    fn on_progress(&mut self, progress: &Progress) {
+        self.0.set_length(progress.total);
+        self.0.set_position(progress.transferred);
+        let eta = match progress.eta {
+            Some(eta) => indicatif::HumanDuration(eta).to_string(),
+            None => "?".to_string(),
+        };
+        self.0.set_message(format!(
+            "{}/s, ETA {}",
+            indicatif::HumanBytes(progress.rate as u64),
+            eta
+        ));
+    }
+
+    fn on_finish(&mut self, _progress: &Progress) {
+        self.0.finish_and_clear();
+    }
+}
+
+/// Bytes moved so far in a single transfer
+struct Tracker {
+    started: std::time::Instant,
+    offset: u64,
+    transferred: u64,
+    total: u64,
+}
+
+impl Tracker {
+    fn new(offset: u64, total: u64) -> Self {
+        Tracker {
+            started: std::time::Instant::now(),
+            offset,
+            transferred: 0,
+            total,
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0f64 {
+            self.transferred as f64 / elapsed
+        } else {
+            0f64
+        };
+        let remaining = self.total.saturating_sub(self.offset + self.transferred);
+        let eta = if remaining == 0 {
+            Some(std::time::Duration::ZERO)
+        } else if rate > 0f64 {
+            Some(std::time::Duration::from_secs_f64(remaining as f64 / rate))
+        } else {
+            None
+        };
+        Progress {
+            transferred: self.offset + self.transferred,
+            total: self.total,
+            rate,
+            eta,
+        }
+    }
+}
+
+/// Wraps a writer, reporting every write to a `ProgressObserver`
+pub struct ProgressWriter<'a, W: ?Sized> {
+    writer: &'a mut W,
+    observer: &'a mut dyn ProgressObserver,
+    tracker: Tracker,
+}
+
+impl<'a, W: ?Sized + std::io::Write> ProgressWriter<'a, W> {
+    pub fn new(
+        writer: &'a mut W,
+        observer: &'a mut dyn ProgressObserver,
+        offset: u64,
+        total: u64,
+    ) -> Self {
+        ProgressWriter {
+            writer,
+            observer,
+            tracker: Tracker::new(offset, total),
+        }
+    }
+
+    pub fn finish(self) {
+        self.observer.on_finish(&self.tracker.progress());
+    }
+}
+
+impl<W: ?Sized + std::io::Write> std::io::Write for ProgressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.writer.write(buf)?;
+        self.tracker.transferred += count as u64;
+        self.observer.on_progress(&self.tracker.progress());
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Wraps a reader, reporting every read to a `ProgressObserver`
+pub struct ProgressReader<'a, R: ?Sized> {
+    reader: &'a mut R,
+    observer: &'a mut dyn ProgressObserver,
+    tracker: Tracker,
+}
+
+impl<'a, R: ?Sized + std::io::Read> ProgressReader<'a, R> {
+    pub fn new(
+        reader: &'a mut R,
+        observer: &'a mut dyn ProgressObserver,
+        offset: u64,
+        total: u64,
+    ) -> Self {
+        ProgressReader {
+            reader,
+            observer,
+            tracker: Tracker::new(offset, total),
+        }
+    }
+
+    pub fn finish(self) {
+        self.observer.on_finish(&self.tracker.progress());
+    }
+}
+
+impl<R: ?Sized + std::io::Read> std::io::Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.reader.read(buf)?;
+        self.tracker.transferred += count as u64;
+        self.observer.on_progress(&self.tracker.progress());
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::progress::{BarProgress, ProgressWriter};
+
+    #[test]
+    fn test_bar_cleared_when_transfer_fails() {
+        let bar = indicatif::ProgressBar::hidden();
+        {
+            let mut observer = BarProgress(bar.clone());
+            let mut sink = std::io::sink();
+            let mut writer = ProgressWriter::new(&mut sink, &mut observer, 0, 10);
+            std::io::Write::write_all(&mut writer, b"12345").unwrap();
+            // the transfer stops here without `finish`
+        }
+        assert!(bar.is_finished());
+    }
+}
@@ -0,0 +1,164 @@
+/// A token bucket that can be shared between threads
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: std::sync::Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: std::time::Instant,
+}
+
+impl RateLimiter {
+    /// Allow `rate` tokens per second, with up to `burst` tokens saved up.
+    /// A rate of zero (or less) disables limiting.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimiter {
+            rate,
+            burst,
+            bucket: std::sync::Mutex::new(Bucket {
+                tokens: burst,
+                updated: std::time::Instant::now(),
+            }),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        RateLimiter::new(0f64, 0f64)
+    }
+
+    /// Take `tokens` out of the bucket, returning how long the caller has to
+    /// wait before using them. The bucket may go into debt, which keeps
+    /// concurrent callers queued up in the order they arrived.
+    pub fn reserve(&self, tokens: f64) -> std::time::Duration {
+        if self.rate <= 0f64 {
+            return std::time::Duration::ZERO;
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens -= tokens;
+        if bucket.tokens >= 0f64 {
+            std::time::Duration::ZERO
+        } else {
+            std::time::Duration::from_secs_f64(-bucket.tokens / self.rate)
+        }
+    }
+}
+
+/// A daily window of local time in whole hours, such as `22-6`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DailyWindow {
+    start: u32,
+    end: u32,
+}
+
+impl DailyWindow {
+    /// Whether `hour` (0 to 23) falls inside the window, which may wrap past midnight
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= hour && hour < self.end
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl std::str::FromStr for DailyWindow {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (start, end) = text
+            .split_once('-')
+            .ok_or_else(|| format!("expected START-END hours, got {:?}", text))?;
+        let start: u32 = start
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid start hour {:?}", start))?;
+        let end: u32 = end
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid end hour {:?}", end))?;
+        if start > 23 || end > 24 {
+            return Err(format!("hours out of range in {:?}", text));
+        }
+        Ok(DailyWindow { start, end })
+    }
+}
+
+/// Limits bytes per second, except during an optional full-speed window
+pub struct BandwidthLimiter {
+    limiter: RateLimiter,
+    full_speed: Option<DailyWindow>,
+}
+
+impl BandwidthLimiter {
+    /// Allow up to one second of transfer to be saved up
+    pub fn new(bytes_per_second: f64, full_speed: Option<DailyWindow>) -> Self {
+        BandwidthLimiter {
+            limiter: RateLimiter::new(bytes_per_second, bytes_per_second),
+            full_speed,
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        BandwidthLimiter::new(0f64, None)
+    }
+
+    /// Account for `bytes` transferred, returning how long to pause afterwards
+    pub fn reserve(&self, bytes: usize) -> std::time::Duration {
+        if let Some(full_speed) = &self.full_speed {
+            if full_speed.contains(chrono::Timelike::hour(&chrono::Local::now())) {
+                return std::time::Duration::ZERO;
+            }
+        }
+        self.limiter.reserve(bytes as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limiter::{DailyWindow, RateLimiter};
+
+    #[test]
+    fn test_reserve() {
+        let limiter = RateLimiter::new(10f64, 2f64);
+        assert!(limiter.reserve(1f64).is_zero());
+        assert!(limiter.reserve(1f64).is_zero());
+        let delay = limiter.reserve(1f64);
+        assert!(delay > std::time::Duration::from_millis(50));
+        assert!(delay <= std::time::Duration::from_millis(100));
+        let delay = limiter.reserve(1f64);
+        assert!(delay > std::time::Duration::from_millis(150));
+        assert!(delay <= std::time::Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::unlimited();
+        for _ in 0..1000 {
+            assert!(limiter.reserve(1f64).is_zero());
+        }
+    }
+
+    #[test]
+    fn test_daily_window() {
+        let night: DailyWindow = "22-6".parse().unwrap();
+        assert!(night.contains(22));
+        assert!(night.contains(0));
+        assert!(night.contains(5));
+        assert!(!night.contains(6));
+        assert!(!night.contains(12));
+        let day: DailyWindow = "9-17".parse().unwrap();
+        assert!(day.contains(9));
+        assert!(!day.contains(17));
+        assert!(!day.contains(3));
+        let always: DailyWindow = "0-24".parse().unwrap();
+        assert!((0..24).all(|hour| always.contains(hour)));
+        assert!("25-3".parse::<DailyWindow>().is_err());
+        assert!("night".parse::<DailyWindow>().is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use serde::{Deserialize, Serialize};
+
+/// Headers whose values identify the user, and are never written to disk
+const REDACTED_HEADERS: [&str; 3] = ["authorization", "cookie", "set-cookie"];
+const REDACTED: &str = "REDACTED";
+
+/// One request and the response it received
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Exchange {
+    method: String,
+    url: String,
+    request_headers: Vec<(String, String)>,
+    request_body: Option<String>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// The file holding the response body, which is not recorded for media
+    body: Option<String>,
+}
+
+impl Exchange {
+    fn key(&self) -> String {
+        format!(
+            "{} {} {}",
+            self.method,
+            self.url,
+            self.request_body.as_deref().unwrap_or("")
+        )
+    }
+}
+
+fn redacted_headers(headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn to_response(status: u16, headers: &[(String, String)], body: Vec<u8>) -> reqwest::Response {
+    let mut builder = http::Response::builder().status(status);
+    for (name, value) in headers.iter() {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    match builder.body(body) {
+        Ok(response) => response.into(),
+        // a recording edited into something invalid is a server error
+        Err(_) => http::Response::builder()
+            .status(500)
+            .body(vec![])
+            .unwrap()
+            .into(),
+    }
+}
+
+#[derive(Debug)]
+enum Mode {
+    Record(std::sync::atomic::AtomicUsize),
+    /// Recorded exchanges for each request, in the order they happened
+    Replay(
+        std::sync::Mutex<std::collections::HashMap<String, std::collections::VecDeque<Exchange>>>,
+    ),
+}
+
+/// Saves every HTTP exchange to a directory, or serves them back from one
+#[derive(Debug)]
+pub struct Recorder {
+    directory: std::path::PathBuf,
+    mode: Mode,
+}
+
+impl Recorder {
+    pub fn record(directory: std::path::PathBuf) -> Result<Self, std::io::Error> {
+        std::fs::create_dir_all(&directory)?;
+        Ok(Recorder {
+            directory,
+            mode: Mode::Record(std::sync::atomic::AtomicUsize::new(0)),
+        })
+    }
+
+    pub fn replay(directory: std::path::PathBuf) -> Result<Self, std::io::Error> {
+        let mut filenames = vec![];
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                filenames.push(path);
+            }
+        }
+        // filenames start with the sequence number of the exchange,
+        // which outgrows its zero padding in long recordings
+        filenames.sort_by_key(|filename| {
+            let sequence = filename
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+            (sequence, filename.clone())
+        });
+        let mut exchanges: std::collections::HashMap<String, std::collections::VecDeque<Exchange>> =
+            std::collections::HashMap::new();
+        for filename in filenames {
+            let exchange: Exchange = serde_json::from_reader(std::fs::File::open(filename)?)?;
+            exchanges
+                .entry(exchange.key())
+                .or_default()
+                .push_back(exchange);
+        }
+        Ok(Recorder {
+            directory,
+            mode: Mode::Replay(std::sync::Mutex::new(exchanges)),
+        })
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(self.mode, Mode::Replay(_))
+    }
+
+    pub async fn execute(
+        &self,
+        client: &reqwest::Client,
+        request: reqwest::Request,
+    ) -> reqwest::Result<reqwest::Response> {
+        let mut exchange = Exchange {
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            request_headers: redacted_headers(request.headers()),
+            request_body: request
+                .body()
+                .and_then(|body| body.as_bytes())
+                .map(|body| String::from_utf8_lossy(body).into_owned()),
+            status: 0,
+            headers: vec![],
+            body: None,
+        };
+        match &self.mode {
+            Mode::Record(sequence) => {
+                let response = client.execute(request).await?;
+                exchange.status = response.status().as_u16();
+                exchange.headers = redacted_headers(response.headers());
+                let sequence = sequence.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                // media is far too large to keep, so only its headers are recorded
+                if response
+                    .headers()
+                    .contains_key(reqwest::header::CONTENT_DISPOSITION)
+                {
+                    self.save(sequence, &exchange, None);
+                    return Ok(response);
+                }
+                let headers = response.headers().clone();
+                let body = response.bytes().await?.to_vec();
+                self.save(sequence, &exchange, Some(&body));
+                let mut response = to_response(exchange.status, &[], body);
+                *response.headers_mut() = headers;
+                Ok(response)
+            }
+            Mode::Replay(exchanges) => {
+                let recorded = {
+                    let mut exchanges = exchanges.lock().unwrap();
+                    exchanges.get_mut(&exchange.key()).and_then(|recorded| {
+                        // the last response keeps being served once the others are used up
+                        if recorded.len() > 1 {
+                            recorded.pop_front()
+                        } else {
+                            recorded.front().cloned()
+                        }
+                    })
+                };
+                let recorded = match recorded {
+                    Some(recorded) => recorded,
+                    None => return Ok(to_response(404, &[], vec![])),
+                };
+                let body = match &recorded.body {
+                    Some(filename) => {
+                        std::fs::read(self.directory.join(filename)).unwrap_or_default()
+                    }
+                    None => vec![],
+                };
+                Ok(to_response(recorded.status, &recorded.headers, body))
+            }
+        }
+    }
+
+    /// Write an exchange to disk; a failure only loses that part of the recording
+    fn save(&self, sequence: usize, exchange: &Exchange, body: Option<&[u8]>) {
+        let name = format!("{:05}", sequence);
+        let mut exchange = exchange.clone();
+        if let Some(body) = body {
+            let filename = format!("{}.body", name);
+            if let Err(err) = std::fs::write(self.directory.join(&filename), body) {
+                eprintln!("Could not record response body: {}", err);
+            }
+            exchange.body = Some(filename);
+        }
+        let result = std::fs::File::create(self.directory.join(format!("{}.json", name)))
+            .map_err(serde_json::Error::io)
+            .and_then(|file| serde_json::to_writer_pretty(file, &exchange));
+        if let Err(err) = result {
+            eprintln!("Could not record request: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::recorder::{Exchange, Recorder};
+
+    #[test]
+    fn test_replay() {
+        let directory = std::env::temp_dir().join("bandcamper-test-replay");
+        let _ = std::fs::remove_dir_all(&directory);
+        let recorder = Recorder::record(directory.clone()).unwrap();
+        let exchange = |status: u16| Exchange {
+            method: "POST".to_string(),
+            url: "https://bandcamp.com/api/test".to_string(),
+            request_headers: vec![],
+            request_body: Some(r#"{"page":1}"#.to_string()),
+            status,
+            headers: vec![("set-cookie".to_string(), "REDACTED".to_string())],
+            body: None,
+        };
+        recorder.save(0, &exchange(502), Some(b"bad gateway"));
+        recorder.save(1, &exchange(200), Some(b"{}"));
+
+        let recorder = Recorder::replay(directory.clone()).unwrap();
+        assert!(recorder.is_replaying());
+        let client = reqwest::Client::new();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let send = |body: &'static str| {
+            let request = client
+                .post("https://bandcamp.com/api/test")
+                .body(body)
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let response = recorder.execute(&client, request).await.unwrap();
+                (response.status().as_u16(), response.text().await.unwrap())
+            })
+        };
+        assert_eq!(send(r#"{"page":1}"#), (502, "bad gateway".to_string()));
+        assert_eq!(send(r#"{"page":1}"#), (200, "{}".to_string()));
+        assert_eq!(send(r#"{"page":1}"#), (200, "{}".to_string()));
+        assert_eq!(send(r#"{"page":2}"#), (404, "".to_string()));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_replay_order_past_padding() {
+        let directory = std::env::temp_dir().join("bandcamper-test-replay-order");
+        let _ = std::fs::remove_dir_all(&directory);
+        let recorder = Recorder::record(directory.clone()).unwrap();
+        let exchange = |status: u16| Exchange {
+            method: "GET".to_string(),
+            url: "https://bandcamp.com/".to_string(),
+            request_headers: vec![],
+            request_body: None,
+            status,
+            headers: vec![],
+            body: None,
+        };
+        recorder.save(99_999, &exchange(502), None);
+        recorder.save(100_000, &exchange(200), None);
+
+        let recorder = Recorder::replay(directory.clone()).unwrap();
+        let client = reqwest::Client::new();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let status = || {
+            let request = client.get("https://bandcamp.com/").build().unwrap();
+            runtime.block_on(async {
+                let response = recorder.execute(&client, request).await.unwrap();
+                response.status().as_u16()
+            })
+        };
+        assert_eq!(status(), 502);
+        assert_eq!(status(), 200);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
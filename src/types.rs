@@ -38,18 +38,97 @@ impl fmt::Display for Encoding {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Sizes of cover art offered by Bandcamp's image server
+#[derive(Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ArtSize {
+    /// As uploaded by the artist
+    Original,
+    /// 1200x1200
+    Large,
+    /// 700x700
+    Medium,
+    /// 350x350
+    Small,
+}
+
+impl ArtSize {
+    /// The image server's code for this size
+    pub fn code(&self) -> u32 {
+        match self {
+            ArtSize::Original => 0,
+            ArtSize::Large => 10,
+            ArtSize::Medium => 5,
+            ArtSize::Small => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub enum CollectionItemKind {
+    #[default]
     #[serde(alias = "album")]
     Album,
     #[serde(alias = "track")]
     Track,
+    /// A discography or other bundle of releases, which are saved separately
+    #[serde(alias = "band", alias = "bundle")]
+    Bundle,
+    /// Physical merch, which may come with digital copies of releases
+    #[serde(alias = "package")]
+    Package,
 }
 
-#[derive(Debug)]
+impl CollectionItemKind {
+    /// Whether the item is saved as the releases it contains rather than as itself
+    pub fn contains_releases(&self) -> bool {
+        matches!(
+            self,
+            CollectionItemKind::Bundle | CollectionItemKind::Package
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct CollectionItem {
     pub itype: CollectionItemKind,
     pub title: String,
     pub artist: String,
-    pub download_url: String,
+    /// None for purchases with nothing to download
+    pub download_url: Option<String>,
+    /// Identifies the album or track across collections
+    pub item_id: u64,
+    pub tralbum_id: u64,
+    pub tralbum_type: String,
+    /// Identifies the purchase, together with `sale_item_type`
+    pub sale_item_id: u64,
+    pub sale_item_type: String,
+    pub band_id: Option<u64>,
+    pub item_url: Option<String>,
+    pub art_id: Option<u64>,
+    /// As given by Bandcamp, e.g. "07 Nov 2022 16:23:00 GMT"
+    pub purchased: Option<String>,
+    pub price: Option<f64>,
+    pub currency: Option<String>,
+    pub label: Option<String>,
+    pub genre_id: Option<u64>,
+    /// The title of the purchase this release was part of, if it came with others
+    pub bundle: Option<String>,
+}
+
+/// An album or track on a fan's wishlist
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WishlistItem {
+    pub itype: CollectionItemKind,
+    pub title: String,
+    pub artist: String,
+    pub url: Option<String>,
+    pub price: Option<f64>,
+    pub currency: Option<String>,
+}
+
+/// File formats for exporting listings
+#[derive(Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Json,
+    Csv,
 }
@@ -0,0 +1,197 @@
+use zip::result::{ZipError, ZipResult};
+
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+const CENTRAL_DIRECTORY_END_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_EXTRA_FIELD_TAG: u16 = 0x0001;
+
+/// What is known about an archive member, from either its local file header
+/// or its central directory record
+#[derive(Debug, PartialEq, Eq)]
+pub struct ZipEntry {
+    pub name: Vec<u8>,
+    pub crc32: u32,
+    pub size: u64,
+}
+
+impl ZipEntry {
+    pub fn from_zipfile(file: &zip::read::ZipFile) -> Self {
+        ZipEntry {
+            name: file.name_raw().into(),
+            crc32: file.crc32(),
+            size: file.size(),
+        }
+    }
+}
+
+fn read_u16(data: &[u8], at: usize) -> ZipResult<u16> {
+    data.get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(ZipError::InvalidArchive("truncated central directory"))
+}
+
+fn read_u32(data: &[u8], at: usize) -> ZipResult<u32> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(ZipError::InvalidArchive("truncated central directory"))
+}
+
+fn read_u64(data: &[u8], at: usize) -> ZipResult<u64> {
+    Ok(read_u32(data, at)? as u64 | (read_u32(data, at + 4)? as u64) << 32)
+}
+
+/// Find the real uncompressed size of a member whose central directory
+/// record defers to the ZIP64 extended information extra field
+fn zip64_size(extra: &[u8]) -> ZipResult<u64> {
+    let mut at = 0;
+    while at + 4 <= extra.len() {
+        let tag = read_u16(extra, at)?;
+        let length = read_u16(extra, at + 2)? as usize;
+        if tag == ZIP64_EXTRA_FIELD_TAG {
+            // the uncompressed size always comes first when it is present
+            return read_u64(extra, at + 4);
+        }
+        at += 4 + length;
+    }
+    Err(ZipError::InvalidArchive("missing zip64 extra field"))
+}
+
+/// Read the central directory that follows the last member of a streamed
+/// archive. `read_zipfile_from_stream` has already consumed the signature
+/// of the first record by the time it reports the end of the members.
+pub fn read_central_directory<R: std::io::Read>(reader: &mut R) -> ZipResult<Vec<ZipEntry>> {
+    let mut data = CENTRAL_DIRECTORY_HEADER_SIGNATURE.to_le_bytes().to_vec();
+    reader.read_to_end(&mut data)?;
+    let mut entries = vec![];
+    let mut at = 0;
+    while read_u32(&data, at)? == CENTRAL_DIRECTORY_HEADER_SIGNATURE {
+        let crc32 = read_u32(&data, at + 16)?;
+        let size = read_u32(&data, at + 24)?;
+        let name_length = read_u16(&data, at + 28)? as usize;
+        let extra_length = read_u16(&data, at + 30)? as usize;
+        let comment_length = read_u16(&data, at + 32)? as usize;
+        let name_start = at + 46;
+        let extra_start = name_start + name_length;
+        let name = data
+            .get(name_start..extra_start)
+            .ok_or(ZipError::InvalidArchive("truncated central directory"))?;
+        let extra = data
+            .get(extra_start..extra_start + extra_length)
+            .ok_or(ZipError::InvalidArchive("truncated central directory"))?;
+        let size = match size {
+            0xFFFFFFFF => zip64_size(extra)?,
+            size => size as u64,
+        };
+        entries.push(ZipEntry {
+            name: name.into(),
+            crc32,
+            size,
+        });
+        at = extra_start + extra_length + comment_length;
+    }
+    match read_u32(&data, at)? {
+        ZIP64_CENTRAL_DIRECTORY_END_SIGNATURE => {
+            let count = read_u64(&data, at + 32)?;
+            if count != entries.len() as u64 {
+                return Err(ZipError::InvalidArchive(
+                    "central directory entry count mismatch",
+                ));
+            }
+        }
+        CENTRAL_DIRECTORY_END_SIGNATURE => {
+            let comment_length = read_u16(&data, at + 20)? as usize;
+            if data.len() < at + 22 + comment_length {
+                return Err(ZipError::InvalidArchive("truncated central directory"));
+            }
+            let count = read_u16(&data, at + 10)?;
+            if count != 0xFFFF && count as usize != entries.len() {
+                return Err(ZipError::InvalidArchive(
+                    "central directory entry count mismatch",
+                ));
+            }
+        }
+        _ => return Err(ZipError::InvalidArchive("invalid central directory")),
+    }
+    Ok(entries)
+}
+
+/// Check that the members read from the stream are exactly the ones the
+/// central directory says the archive contains
+pub fn validate(streamed: &[ZipEntry], central_directory: &[ZipEntry]) -> ZipResult<()> {
+    if streamed.len() != central_directory.len() {
+        return Err(ZipError::InvalidArchive(
+            "central directory does not match archive members",
+        ));
+    }
+    for (local, central) in streamed.iter().zip(central_directory) {
+        if local != central {
+            return Err(ZipError::InvalidArchive(
+                "central directory does not match archive members",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Wraps a reader, writing a copy of everything read through it
+pub struct CopyingReader<R, W> {
+    inner: R,
+    copy: W,
+}
+
+impl<R: std::io::Read, W: std::io::Write> CopyingReader<R, W> {
+    pub fn new(inner: R, copy: W) -> Self {
+        CopyingReader { inner, copy }
+    }
+}
+
+impl<R: std::io::Read, W: std::io::Write> std::io::Read for CopyingReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.copy.write_all(&buf[..count])?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::zip_stream::{read_central_directory, validate, ZipEntry};
+
+    #[test]
+    fn test_read_central_directory() {
+        let mut reader = std::fs::File::open("testdata/archive.zip").unwrap();
+        let mut streamed = vec![];
+        while let Some(file) = zip::read::read_zipfile_from_stream(&mut reader).unwrap() {
+            streamed.push(ZipEntry::from_zipfile(&file));
+        }
+        let central_directory = read_central_directory(&mut reader).unwrap();
+        assert_eq!(
+            central_directory,
+            vec![
+                ZipEntry {
+                    name: b"file1.flac".to_vec(),
+                    crc32: 0xf817a89f,
+                    size: 4,
+                },
+                ZipEntry {
+                    name: b"file2.flac".to_vec(),
+                    crc32: 0x96170874,
+                    size: 4,
+                },
+            ]
+        );
+        validate(&streamed, &central_directory).unwrap();
+        assert!(validate(&streamed[..1], &central_directory).is_err());
+    }
+
+    #[test]
+    fn test_read_truncated_central_directory() {
+        let data = std::fs::read("testdata/archive.zip").unwrap();
+        let mut reader = &data[..data.len() - 10];
+        while zip::read::read_zipfile_from_stream(&mut reader)
+            .unwrap()
+            .is_some()
+        {}
+        assert!(read_central_directory(&mut reader).is_err());
+    }
+}